@@ -0,0 +1,463 @@
+// Camera paths for animations, from keyframes or from a GPX track
+use crate::config::{CONFIG, UTM_ZONE};
+use crate::view::View;
+
+use hoydedata::{Coord, Error, Result};
+use chrono::{DateTime, FixedOffset};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use std::fs::read_to_string;
+use std::ops::{Add, Mul};
+
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%z";
+
+// Output of an animation: numbered image files, or an animated GIF or PNG
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Frames,
+    Gif,
+    Apng,
+}
+
+impl Format {
+    pub fn from(name: &str) -> Result<Format> {
+	match name {
+	    "frames" => Ok(Format::Frames),
+	    "gif" => Ok(Format::Gif),
+	    "apng" => Ok(Format::Apng),
+	    _ => Err(Error::Generic(
+		format!("Unknown animation format {}", name))),
+	}
+    }
+
+    pub fn from_config() -> Result<Format> {
+	Format::from(&CONFIG.animation_format)
+    }
+}
+
+// A keyframe, at a frame number
+struct Keyframe {
+    frame: u32,
+    observer: Coord,
+    target: Coord,
+    time: DateTime<FixedOffset>,
+    width_angle: f32,
+}
+
+fn parse_time(time: &str) -> Result<DateTime<FixedOffset>> {
+    DateTime::parse_from_str(time.trim(), TIME_FORMAT)
+	.or_else(|_| DateTime::parse_from_rfc3339(time.trim()))
+	.map_err(|_| Error::Generic(format!("Bad time {}", time)))
+}
+
+fn format_time(ms: i64, offset: &FixedOffset) -> String {
+    match DateTime::from_timestamp_millis(ms) {
+	Some(t) => t.with_timezone(offset).format(TIME_FORMAT).to_string(),
+	None => CONFIG.time.clone(),
+    }
+}
+
+// Catmull-Rom spline through p1 and p2, at t from 0 to 1. The curve passes
+// through the points, with a tangent parallel to the line between the
+// neighbour points.
+fn catmull_rom<T>(p0: T, p1: T, p2: T, p3: T, t: f32) -> T
+where T: Copy + Add<Output = T> + Mul<f32, Output = T> {
+    let t2 = t*t;
+    let t3 = t2*t;
+
+    p0*(0.5*(-t + 2.0*t2 - t3)) +
+	p1*(0.5*(2.0 - 5.0*t2 + 3.0*t3)) +
+	p2*(0.5*(t + 4.0*t2 - 3.0*t3)) +
+	p3*(0.5*(-t2 + t3))
+}
+
+/*
+Read keyframes from a file. Each line holds the frame number, observer,
+target, time and width_angle, separated by semicolons. Empty fields are
+taken from the previous keyframe, or from config for the first keyframe.
+Lines starting with # are comments.
+ */
+fn read_keyframes(file: &str) -> Result<Vec<Keyframe>> {
+    let text = read_to_string(file)
+	.map_err(|e| Error::Generic(format!("{}: {}", file, e)))?;
+    let mut keyframes: Vec<Keyframe> = Vec::new();
+
+    for line in text.lines() {
+	let line = line.trim();
+	if line.is_empty() || line.starts_with('#') {
+	    continue;
+	}
+
+	let fields: Vec<&str> = line.split(';').map(|f| f.trim()).collect();
+	let field = |i: usize| fields.get(i).copied().filter(|f| !f.is_empty());
+	let bad = || Error::Generic(format!("Bad keyframe: {}", line));
+
+	let frame = field(0).ok_or_else(bad)?.parse::<u32>().map_err(|_| bad())?;
+
+	let (mut observer, mut target) = (CONFIG.observer, CONFIG.target);
+	let mut time = parse_time(&CONFIG.time)?;
+	let mut width_angle = CONFIG.width_angle;
+
+	if let Some(prev) = keyframes.last() {
+	    if frame <= prev.frame {
+		return Err(Error::Generic(
+		    format!("Keyframes must be in increasing order: {}", line)));
+	    }
+
+	    observer = prev.observer;
+	    target = prev.target;
+	    time = prev.time;
+	    width_angle = prev.width_angle;
+	}
+
+	if let Some(f) = field(1) {
+	    observer = Coord::from(f);
+	}
+	if let Some(f) = field(2) {
+	    target = Coord::from(f);
+	}
+	if let Some(f) = field(3) {
+	    time = parse_time(f)?;
+	}
+	if let Some(f) = field(4) {
+	    width_angle = f.parse::<f32>().map_err(|_| bad())?;
+	}
+
+	keyframes.push(Keyframe {
+	    frame,
+	    observer,
+	    target,
+	    time,
+	    width_angle,
+	});
+    }
+
+    if keyframes.is_empty() {
+	return Err(Error::Generic(format!("No keyframes in {}", file)));
+    }
+
+    Ok(keyframes)
+}
+
+// Views for all frames from the first to the last keyframe. Positions and
+// view angles follow Catmull-Rom splines through the keyframes, while the
+// time is interpolated linearly.
+fn keyframe_views(keyframes: &[Keyframe]) -> Vec<View> {
+    let n = keyframes.len();
+    let mut views = Vec::new();
+
+    for i in 0..n {
+	let k1 = &keyframes[i];
+
+	if i + 1 == n {
+	    views.push(View {
+		observer: k1.observer,
+		target: k1.target,
+		observer_height: None,
+		time: k1.time.format(TIME_FORMAT).to_string(),
+		width_angle: k1.width_angle,
+	    });
+	    break;
+	}
+
+	let k0 = &keyframes[i.saturating_sub(1)];
+	let k2 = &keyframes[i + 1];
+	let k3 = &keyframes[(i + 2).min(n - 1)];
+	let t1 = k1.time.timestamp_millis();
+	let t2 = k2.time.timestamp_millis();
+
+	for frame in k1.frame..k2.frame {
+	    let t = ((frame - k1.frame) as f32)/((k2.frame - k1.frame) as f32);
+	    let ms = t1 + (((t2 - t1) as f64)*(t as f64)) as i64;
+
+	    views.push(View {
+		observer: catmull_rom(k0.observer, k1.observer,
+				      k2.observer, k3.observer, t),
+		target: catmull_rom(k0.target, k1.target, k2.target, k3.target, t),
+		observer_height: None,
+		time: format_time(ms, k1.time.offset()),
+		width_angle: catmull_rom(k0.width_angle, k1.width_angle,
+					 k2.width_angle, k3.width_angle, t),
+	    });
+	}
+    }
+
+    views
+}
+
+// A point of a GPX track: position, height and time (if any)
+struct TrackPoint {
+    pos: Coord,
+    height: Option<f32>,
+    time: Option<DateTime<FixedOffset>>,
+}
+
+// Position of a track point from its lat and lon attributes, in the UTM
+// zone of the maps
+fn track_position(e: &BytesStart) -> std::result::Result<Coord, String> {
+    let mut lat = None;
+    let mut lon = None;
+
+    for a in e.attributes() {
+	let a = a.map_err(|e| e.to_string())?;
+	let value = || -> std::result::Result<f64, String> {
+	    let v = a.unescape_value().map_err(|e| e.to_string())?;
+	    v.trim().parse::<f64>().map_err(|_| format!("Bad coordinate {}", v))
+	};
+
+	match a.key.local_name().as_ref() {
+	    b"lat" => lat = Some(value()?),
+	    b"lon" => lon = Some(value()?),
+	    _ => {},
+	}
+    }
+
+    match (lat, lon) {
+	(Some(lat), Some(lon)) => {
+	    let (n, e, _) = utm::to_utm_wgs84(lat, lon, UTM_ZONE);
+	    let mut pos = CONFIG.observer;
+	    pos.e = e as f32;
+	    pos.n = n as f32;
+	    Ok(pos)
+	},
+	_ => Err("Track point without lat and lon".to_string()),
+    }
+}
+
+/*
+Read the track points of a GPX file. The positions are converted to the UTM
+zone of the maps. Namespace prefixes are ignored. A track point which
+cannot be read, e.g. with a bad coordinate, height or time, is an error.
+ */
+fn read_gpx(file: &str) -> Result<Vec<TrackPoint>> {
+    let text = read_to_string(file)
+	.map_err(|e| Error::Generic(format!("{}: {}", file, e)))?;
+    let mut reader = Reader::from_str(&text);
+    reader.config_mut().trim_text(true);
+
+    let mut points = Vec::new();
+    let mut point: Option<TrackPoint> = None;
+    // Name of the element of the track point we are in, if any
+    let mut field: Option<Vec<u8>> = None;
+
+    loop {
+	let event = reader.read_event().map_err(|e| e.to_string());
+	let res = match event {
+	    Ok(Event::Start(e)) if e.local_name().as_ref() == b"trkpt" => {
+		track_position(&e).map(|pos| {
+		    point = Some(TrackPoint { pos, height: None, time: None });
+		})
+	    },
+	    Ok(Event::Empty(e)) if e.local_name().as_ref() == b"trkpt" => {
+		track_position(&e).map(|pos| {
+		    points.push(TrackPoint { pos, height: None, time: None });
+		})
+	    },
+	    Ok(Event::Start(e)) => {
+		if point.is_some() {
+		    field = Some(e.local_name().as_ref().to_vec());
+		}
+		Ok(())
+	    },
+	    Ok(Event::Text(t)) => {
+		match (point.as_mut(), field.as_deref()) {
+		    (Some(p), Some(b"ele")) => {
+			t.unescape().map_err(|e| e.to_string()).and_then(|v| {
+			    v.parse::<f32>()
+				.map(|h| p.height = Some(h))
+				.map_err(|_| format!("Bad height {}", v))
+			})
+		    },
+		    (Some(p), Some(b"time")) => {
+			t.unescape().map_err(|e| e.to_string()).and_then(|v| {
+			    parse_time(&v)
+				.map(|time| p.time = Some(time))
+				.map_err(|_| format!("Bad time {}", v))
+			})
+		    },
+		    _ => Ok(()),
+		}
+	    },
+	    Ok(Event::End(e)) => {
+		if e.local_name().as_ref() == b"trkpt" {
+		    points.extend(point.take());
+		}
+		field = None;
+		Ok(())
+	    },
+	    Ok(Event::Eof) => break,
+	    Ok(_) => Ok(()),
+	    Err(e) => Err(e),
+	};
+
+	if let Err(e) = res {
+	    return Err(Error::Generic(format!(
+		"{}: {} at byte {}", file, e, reader.buffer_position())));
+	}
+    }
+
+    if points.len() < 2 {
+	return Err(Error::Generic(
+	    format!("Need at least two track points in {}", file)));
+    }
+
+    Ok(points)
+}
+
+// Position, height and time at a distance along a track, interpolated
+// between the track points. cum holds the distance to each point.
+fn track_at(points: &[TrackPoint], cum: &[f32], dist: f32)
+	    -> (Coord, Option<f32>, Option<i64>) {
+    let i = cum.partition_point(|&c| c <= dist).clamp(1, points.len() - 1);
+    let (p0, p1) = (&points[i - 1], &points[i]);
+    let len = cum[i] - cum[i - 1];
+    let t = if len > 0.0 { ((dist - cum[i - 1])/len).clamp(0.0, 1.0) } else { 0.0 };
+
+    let pos = p0.pos*(1.0 - t) + p1.pos*t;
+    let height = match (p0.height, p1.height) {
+	(Some(h0), Some(h1)) => Some(h0*(1.0 - t) + h1*t),
+	_ => None,
+    };
+    let time = match (p0.time, p1.time) {
+	(Some(t0), Some(t1)) => {
+	    let (m0, m1) = (t0.timestamp_millis(), t1.timestamp_millis());
+	    Some(m0 + (((m1 - m0) as f64)*(t as f64)) as i64)
+	},
+	_ => None,
+    };
+
+    (pos, height, time)
+}
+
+/*
+Views along a GPX track. The frames are evenly spaced along the track. The
+observer follows the track, at the height of the track if given, and looks
+at the point gpx_lookahead meters further along the track. The time is
+taken from the track if it has times, otherwise from config.
+ */
+fn gpx_views(points: &[TrackPoint]) -> Vec<View> {
+    let mut cum = vec![0.0];
+    for i in 1..points.len() {
+	let d = (points[i].pos - points[i - 1].pos).abs();
+	cum.push(cum[i - 1] + d);
+    }
+
+    let total = cum[cum.len() - 1];
+    let frames = CONFIG.frames.max(1);
+    let offset = parse_time(&CONFIG.time)
+	.map(|t| *t.offset())
+	.unwrap_or(FixedOffset::east_opt(0).unwrap());
+    let mut views = Vec::new();
+
+    for f in 0..frames {
+	let dist = if frames > 1 { total*(f as f32)/((frames - 1) as f32) } else { 0.0 };
+	let (observer, height, time) = track_at(points, &cum, dist);
+	let mut target = track_at(points, &cum, dist + CONFIG.gpx_lookahead).0;
+
+	// Look straight ahead at the end of the track
+	if (target - observer).abs() < 1.0 {
+	    let (before, _, _) = track_at(points, &cum, dist - CONFIG.gpx_lookahead);
+	    target = observer + (observer - before);
+	}
+
+	views.push(View {
+	    observer,
+	    target,
+	    observer_height: height,
+	    time: time.map(|ms| format_time(ms, &offset))
+		.unwrap_or_else(|| CONFIG.time.clone()),
+	    width_angle: CONFIG.width_angle,
+	});
+    }
+
+    views
+}
+
+// Views of a time-lapse: the view from config at times from time_start to
+// time_end, time_step minutes apart.
+fn timelapse_views() -> Result<Vec<View>> {
+    let start = parse_time(&CONFIG.time_start)?;
+    let end = if CONFIG.time_end.is_empty() {
+	start
+    }
+    else {
+	parse_time(&CONFIG.time_end)?
+    };
+
+    let step = (CONFIG.time_step*60000.0) as i64;
+    if step <= 0 {
+	return Err(Error::Generic("time_step must be positive".to_string()));
+    }
+
+    let t_end = end.timestamp_millis();
+    let mut ms = start.timestamp_millis();
+    if t_end < ms {
+	return Err(Error::Generic("time_end is before time_start".to_string()));
+    }
+
+    let mut views = Vec::new();
+    while ms <= t_end {
+	let mut view = View::from_config();
+	view.time = format_time(ms, start.offset());
+	views.push(view);
+	ms += step;
+    }
+
+    Ok(views)
+}
+
+// Views of all frames of the animation given in config, or None if there
+// is no animation.
+pub fn frames() -> Result<Option<Vec<View>>> {
+    if !CONFIG.keyframes.is_empty() {
+	let keyframes = read_keyframes(&CONFIG.keyframes)?;
+	return Ok(Some(keyframe_views(&keyframes)));
+    }
+
+    if !CONFIG.gpx.is_empty() {
+	let points = read_gpx(&CONFIG.gpx)?;
+	return Ok(Some(gpx_views(&points)));
+    }
+
+    if !CONFIG.time_start.is_empty() {
+	return Ok(Some(timelapse_views()?));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catmull_rom_passes_through_points() {
+	let (p0, p1, p2, p3) = (4.0, 1.0, 2.0, -3.0);
+
+	assert!((catmull_rom(p0, p1, p2, p3, 0.0) - p1).abs() < 1e-6);
+	assert!((catmull_rom(p0, p1, p2, p3, 1.0) - p2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn catmull_rom_keeps_straight_lines() {
+	// Evenly spaced points on a line give even steps along it
+	for i in 0..=10 {
+	    let t = (i as f32)/10.0;
+	    assert!((catmull_rom(0.0, 1.0, 2.0, 3.0, t) - (1.0 + t)).abs() < 1e-6);
+	}
+    }
+
+    #[test]
+    fn parse_time_formats() {
+	let t = parse_time("2024-06-21T12:30:00Z").unwrap();
+	assert_eq!(t.timestamp(), 1718973000);
+
+	let t = parse_time("2024-06-21T14:30:00+0200").unwrap();
+	assert_eq!(t.timestamp(), 1718973000);
+
+	let t = parse_time(" 2024-06-21T14:30:00+02:00 ").unwrap();
+	assert_eq!(t.timestamp(), 1718973000);
+
+	assert!(parse_time("noon").is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::config::CONFIG;
+use crate::color::*;
+
+use crate::sky::daylight;
+use crate::renderer::R_EARTH;
+
+use hoydedata::Coord3;
+use std::f32::consts::PI;
+
+// Rayleigh scattering coefficients (1/m) at sea level for red, green and
+// blue light (680, 550 and 440nm)
+const RAYLEIGH_SEA: [f32; 3] = [5.8e-6, 13.5e-6, 33.1e-6];
+
+// Mie scattering coefficient (1/m) at sea level for haziness = 1
+const MIE_SEA: f32 = 1.0e-5;
+
+// Scale heights (m) of the air and the aerosol densities
+const RAYLEIGH_SCALE_HEIGHT: f32 = 8000.0;
+const MIE_SCALE_HEIGHT: f32 = 1200.0;
+
+// Asymmetry of the Mie phase function (forward scattering)
+const MIE_G: f32 = 0.76;
+
+// Brightness of the full moon relative to the sun. This is far brighter
+// than in reality, corresponding to the longer exposure of a night photo.
+const MOON_BRIGHTNESS: f32 = 0.1;
+
+// Color of moonlight and of the diffuse light from the sky
+const MOON_TINT: Color = Color::new(0.80, 0.88, 1.0);
+const SKY_TINT: Color = Color::new(0.75, 0.85, 1.0);
+
+// Ratio of the diffuse sky light which is scattered into the view ray
+const SKY_SCATTER: f32 = 0.2;
+
+// Number of steps when integrating along the view ray
+const STEPS: usize = 16;
+
+/*
+Single scattering model of the atmosphere between the observer and the
+terrain. Light from the terrain is attenuated by Rayleigh (air) and Mie
+(aerosols) extinction, and light from the sun, the moon and the sky is
+scattered into the view ray. The sunlight and moonlight is itself filtered
+through the atmosphere, giving warm light when the sun is low.
+
+The config parameter rayleigh scales the Rayleigh coefficients, and haziness
+scales the Mie coefficient.
+ */
+pub struct Atmosphere {
+    beta_r: [f32; 3],
+    beta_m: f32,
+    sun_ray: Coord3,
+    sun_color: Color,
+    sun_light: Color,
+    moon_ray: Coord3,
+    moon_color: Color,
+    sky_light: Color,
+}
+
+// Relative optical path length through the atmosphere towards a given
+// altitude angle (Kasten and Young).
+pub fn air_mass(alt: f32) -> f32 {
+    let deg = alt.to_degrees().max(0.0);
+
+    1.0/(deg.to_radians().sin() + 0.50572*(deg + 6.07995).powf(-1.6364))
+}
+
+impl Atmosphere {
+    pub fn new(sun_ray: Coord3, sun_alt: f32,
+	       moon_ray: Coord3, moon_alt: f32, illumination: f32) -> Self {
+	let beta_r = RAYLEIGH_SEA.map(|b| b*CONFIG.rayleigh);
+	let beta_m = MIE_SEA*CONFIG.haziness;
+
+	// Transmittance through the whole atmosphere towards an altitude
+	let transmittance = |alt: f32| {
+	    let m = air_mass(alt);
+	    let t = beta_r.map(|b| {
+		(-(b*RAYLEIGH_SCALE_HEIGHT + beta_m*MIE_SCALE_HEIGHT)*m).exp()
+	    });
+	    Color::new(t[0], t[1], t[2])
+	};
+
+	// Sunlight passing through the atmosphere. Below the horizon, there
+	// is no direct sunlight. When lighting the terrain, the sunlight is
+	// white balanced to the sun in zenith.
+	let mut sun_color = BLACK;
+	let mut sun_light = BLACK;
+	if sun_alt > 0.0 {
+	    let t = transmittance(sun_alt);
+	    let [zr, zg, zb] = transmittance(0.5*PI).as_array();
+
+	    sun_color = WHITE*t;
+	    sun_light = t*Color::new(1.0/zr, 1.0/zg, 1.0/zb);
+	}
+
+	// Moonlight, depending on the phase
+	let mut moon_color = BLACK;
+	if moon_alt > 0.0 {
+	    moon_color = WHITE*MOON_TINT*transmittance(moon_alt)*
+		(MOON_BRIGHTNESS*illumination);
+	}
+
+	// Diffuse light from the sky, fading through the twilight. The moon
+	// lights up the night sky.
+	let sky_light = SKY_TINT*(daylight(sun_alt) +
+				  MOON_BRIGHTNESS*illumination*
+				  daylight(moon_alt));
+
+	Self {
+	    beta_r,
+	    beta_m,
+	    sun_ray,
+	    sun_color,
+	    sun_light,
+	    moon_ray,
+	    moon_color,
+	    sky_light,
+	}
+    }
+
+    // Color of direct sunlight and moonlight
+    pub fn sun_color(&self) -> Color {
+	self.sun_color
+    }
+
+    pub fn moon_color(&self) -> Color {
+	self.moon_color
+    }
+
+    // Light on terrain from the sun, the moon and the sky, as a factor of
+    // full daylight for each color component.
+    pub fn sun_light(&self) -> Color {
+	self.sun_light
+    }
+
+    pub fn moon_light(&self) -> Color {
+	self.moon_color
+    }
+
+    pub fn ambient_light(&self) -> Color {
+	self.sky_light*CONFIG.ambient_light
+    }
+
+    // Phase functions multiplied by 4*pi, i.e. 1 for isotropic scattering
+    fn rayleigh_phase(cos: f32) -> f32 {
+	0.75*(1.0 + cos*cos)
+    }
+
+    fn mie_phase(cos: f32) -> f32 {
+	let g2 = MIE_G*MIE_G;
+
+	(1.0 - g2)/(1.0 + g2 - 2.0*MIE_G*cos).powf(1.5)
+    }
+
+    /*
+    Color of an object seen at distance dist through the atmosphere.
+    view:     unit vector of the view direction
+    h0, h1:   height of the observer and the object
+
+    The extinction and the scattering are integrated along the view ray in
+    STEPS steps, with the density at the middle of each step. The straight
+    ray sinks below the line between the heights of its ends, by the
+    curvature of the earth, so a long ray over the sea passes through
+    denser air than at either end.
+     */
+    pub fn apply(&self, color: Color, dist: f32, view: Coord3,
+		 h0: f32, h1: f32) -> Color {
+	let cos = view.dot(self.sun_ray);
+	let pr = Atmosphere::rayleigh_phase(cos);
+	let pm = Atmosphere::mie_phase(cos);
+
+	let cos_m = view.dot(self.moon_ray);
+	let pr_m = Atmosphere::rayleigh_phase(cos_m);
+	let pm_m = Atmosphere::mie_phase(cos_m);
+
+	let ds = dist/(STEPS as f32);
+	let mut t = [1.0; 3];
+	let mut s = [0.0; 3];
+	let mut s_m = [0.0; 3];
+	let mut s_sky = [0.0; 3];
+
+	for k in 0..STEPS {
+	    let u = ((k as f32) + 0.5)/(STEPS as f32);
+	    let h = (h0 + (h1 - h0)*u - 0.5*u*(1.0 - u)*dist*dist/R_EARTH).max(0.0);
+	    let dr = (-h/RAYLEIGH_SCALE_HEIGHT).exp();
+	    let bm = self.beta_m*(-h/MIE_SCALE_HEIGHT).exp();
+
+	    for i in 0..3 {
+		let br = self.beta_r[i]*dr;
+		let ext = br + bm;
+
+		// Transmittance of the step, and the light scattered into
+		// the ray in the step, attenuated on the way to the observer
+		let ts = (-ext*ds).exp();
+		if ext > 0.0 {
+		    let f = t[i]*(1.0 - ts)/ext;
+		    s[i] += f*(br*pr + bm*pm);
+		    s_m[i] += f*(br*pr_m + bm*pm_m);
+		    s_sky[i] += t[i]*(1.0 - ts)*SKY_SCATTER;
+		}
+		t[i] *= ts;
+	    }
+	}
+
+	color*Color::new(t[0], t[1], t[2]) +
+	    self.sun_color*Color::new(s[0], s[1], s[2]) +
+	    self.moon_color*Color::new(s_m[0], s_m[1], s_m[2]) +
+	    WHITE*self.sky_light*Color::new(s_sky[0], s_sky[1], s_sky[2])
+    }
+}
@@ -0,0 +1,80 @@
+extern crate gamlenorge;
+
+// Benchmark ray tracing with fixed steps against the max-height pyramid, and
+// check that the pyramid finds the same hits
+
+use gamlenorge::{Renderer, Detail, Pyramid, View, CONFIG};
+use hoydedata::{set_map_dir, unmount_all_maps, Atlas, Result};
+use std::sync::Arc;
+use std::time::Instant;
+
+fn main() -> Result<()> {
+    set_map_dir(&CONFIG.map_dir());
+
+    let view = View::from_config();
+    let atlas10 = Atlas::new(10.0, None)?;
+    let blocks = Renderer::detail_blocks(&atlas10, &view)?;
+    let (c_min, c_max) = Renderer::view_bounds(&atlas10, &view)?;
+    let mut r = Renderer::new(atlas10, None)?;
+
+    let t = Instant::now();
+    let detail = Detail::build(&blocks, None, 1)?;
+    println!("Loading 1m maps: {:.2?}", t.elapsed());
+    r.set_detail(Arc::new(detail));
+
+    // Render once for loading the maps
+    r.render_all();
+
+    let t = Instant::now();
+    r.render_all();
+    println!("Fixed steps: {:.2?}", t.elapsed());
+    let fixed = r.trace_rays(0, CONFIG.width);
+
+    let t = Instant::now();
+    let p = Pyramid::build(c_min, c_max, 1)?;
+    println!("Building pyramid: {:.2?}", t.elapsed());
+
+    r.set_pyramid(Arc::new(p));
+
+    let t = Instant::now();
+    r.render_all();
+    println!("Pyramid: {:.2?}", t.elapsed());
+    let skipped = r.trace_rays(0, CONFIG.width);
+
+    // With fixed steps, the hits are up to one step beyond the terrain, or
+    // further if a thin ridge is missed. The pyramid refines the hits, and
+    // should never find land further away.
+    let mut same = 0;
+    let mut closer = 0;
+    let mut further = 0;
+    let mut max_diff = 0.0_f32;
+
+    for (f, s) in fixed.iter().zip(&skipped) {
+	match (f, s) {
+	    (Some((fc, fd, step)), Some((sc, sd, _))) => {
+		if sd > fd || (*fc - *sc).abs() > fd - sd + 1.0 {
+		    further += 1;
+		}
+		else if fd - sd <= *step {
+		    same += 1;
+		    max_diff = max_diff.max((*fc - *sc).abs());
+		}
+		else {
+		    closer += 1;
+		}
+	    },
+	    (Some(_), None) => further += 1,
+	    (None, Some(_)) => closer += 1,
+	    (None, None) => same += 1,
+	}
+    }
+
+    println!("Rays: {}, same hit: {} (up to {:.2}m apart)",
+	     fixed.len(), same, max_diff);
+    println!("Thin ridges found with the pyramid: {}", closer);
+    println!("Land missed with the pyramid: {}", further);
+
+    unmount_all_maps();
+
+    Ok(())
+}
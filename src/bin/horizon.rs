@@ -1,16 +1,21 @@
 extern crate gamlenorge;
 
-use gamlenorge::{Renderer, CONFIG};
+use gamlenorge::{Renderer, Detail, View, CONFIG};
 use hoydedata::{set_map_dir, unmount_all_maps, Atlas, Result};
+use std::sync::Arc;
 
 fn main() -> Result<()> {
     set_map_dir(&CONFIG.maps);
 
-    let atlas1 = Atlas::new(1.0, None)?;
     let atlas10 = Atlas::new(10.0, None)?;
+    let blocks = Renderer::detail_blocks(&atlas10, &View::from_config())?;
 
-    let r = Renderer::new(atlas1, atlas10, None);
-    let h = r?.find_horizon()?;
+    let mut r = Renderer::new(atlas10, None)?;
+    r.set_detail(Arc::new(Detail::build(&blocks, None, 1)?));
+    let (azimuth, pitch, roll) = r.orientation();
+    println!("Camera azimuth {:.3}, pitch {:.3}, roll {:.3}", azimuth, pitch, roll);
+
+    let h = r.find_horizon()?;
     println!("Horizon for target {}: {}", CONFIG.target, h);
 
     unmount_all_maps();
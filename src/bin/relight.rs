@@ -0,0 +1,19 @@
+extern crate gamlenorge;
+
+use gamlenorge::{Renderer, CONFIG};
+use hoydedata::{set_map_dir, unmount_all_maps, Result};
+use std::time::Instant;
+
+// Shade a rendered image again from its geometry buffer, with the light
+// given in config
+fn main() -> Result<()> {
+    set_map_dir(&CONFIG.map_dir());
+
+    let t = Instant::now();
+    Renderer::relight()?;
+    println!("Relight: {:.2?}", t.elapsed());
+
+    unmount_all_maps();
+
+    Ok(())
+}
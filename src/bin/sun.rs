@@ -1,7 +1,7 @@
 extern crate gamlenorge;
 
-// Calculate position of the sun (azimuth and altitude) from geographic
-// point and timestamp
+// Calculate position of the sun and the moon (azimuth and altitude) from
+// geographic point and timestamp
 
 use gamlenorge::Renderer;
 use hoydedata::{Result, Coord};
@@ -24,5 +24,10 @@ fn main() -> Result<()> {
     println!("The position of the sun is {} / {}",
 	     az.to_degrees(), alt.to_degrees());
 
+    let (az, alt, illumination) = Renderer::moon_position(time, coord)?;
+
+    println!("The position of the moon is {} / {} ({:.0}% illuminated)",
+	     az.to_degrees(), alt.to_degrees(), 100.0*illumination);
+
     Ok(())
 }
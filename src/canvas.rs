@@ -1,18 +1,38 @@
 extern crate image;
 use crate::config::CONFIG;
-use crate::color::Color;
-use image::Rgb;
+use crate::color::{Color, ToneMap};
+use crate::projection::{Kind, CUBE_FACES};
+use crate::stereo::{Stereo, Eye};
+use crate::animation::Format;
+use crate::raster::{Rasters, Surface};
+use hoydedata::Result;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, DynamicImage, Frame, Rgb, RgbImage};
 use sdl2::video::Window;
 use sdl2::rect::Point;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
 
 pub struct Canvas {
     im: image::ImageBuffer<Rgb<u8>, Vec<u8>>,
     canvas: Option<sdl2::render::Canvas<Window>>,
-    last_y: u32,
+    exposure: f32,
+    tone_map: ToneMap,
+    stereo: Stereo,
+    // Linear colors of the left and right eye, for composing anaglyphs
+    left: Vec<Option<Color>>,
+    right: Vec<Option<Color>>,
+    // Frames of an animated GIF or PNG, saved when the animation is done
+    format: Format,
+    frames: Vec<RgbImage>,
+    // Float rasters of the terrain in each pixel
+    rasters: Option<Rasters>,
+    kind: Kind,
 }
 
 impl Canvas {
-    pub fn new(width: u32, height: u32) -> Self {
+    pub fn new(width: u32, height: u32) -> Result<Self> {
 	let im = image::ImageBuffer::new(width, height);
 
 	let sdl_context = sdl2::init().unwrap();
@@ -38,30 +58,231 @@ impl Canvas {
 	    optc = Some(c);
 	}
 
-	Self {
+	Ok(Self {
 	    im: im,
 	    canvas: optc,
-	    last_y: 0,
+	    exposure: CONFIG.exposure.exp2(),
+	    tone_map: ToneMap::from(&CONFIG.tonemap)?,
+	    stereo: Stereo::from_config()?,
+	    left: Vec::new(),
+	    right: Vec::new(),
+	    format: Format::from_config()?,
+	    frames: Vec::new(),
+	    rasters: Rasters::from_config(width, height)?,
+	    kind: Kind::from_config()?,
+	})
+    }
+
+    // Triangularly distributed dither noise in the range -1 to 1. The
+    // noise is a hash of the pixel position, so that rendering the same
+    // image twice gives identical files.
+    fn dither(x: u32, y: u32) -> [f32; 3] {
+	let mut d = [0.0; 3];
+	let mut h = x.wrapping_mul(0x9e3779b1) ^ y.wrapping_mul(0x85ebca77);
+
+	for di in d.iter_mut() {
+	    let mut u = [0.0; 2];
+	    for uj in u.iter_mut() {
+		h ^= h >> 16;
+		h = h.wrapping_mul(0x7feb352d);
+		h ^= h >> 15;
+		h = h.wrapping_mul(0x846ca68b);
+		h ^= h >> 16;
+		*uj = (h as f32)/(u32::MAX as f32);
+	    }
+	    *di = u[0] - u[1];
 	}
+
+	d
     }
 
     pub fn draw_pixel(&mut self, x: u32, y: u32, color: Color) {
+	// Colors are rendered in linear light. Apply exposure and tone
+	// mapping, then encode to sRGB.
+	let mapped = color.tone_map(self.exposure, &self.tone_map);
+	let dither = Canvas::dither(x, y);
+
 	let pixel = self.im.get_pixel_mut(x, y);
-	*pixel = image::Rgb(color.as_u8_array());
+	*pixel = image::Rgb(mapped.as_u8_array(dither));
 
 	if let Some(a) = self.canvas.as_mut() {
-	    a.set_draw_color(color.as_sdl2_color());
+	    a.set_draw_color(mapped.as_sdl2_color(dither));
 	    let _ = a.draw_point(Point::new(x as i32, y as i32));
+	}
+    }
+
+    /*
+    Draw a pixel of the view from an eye. Side by side stereo pairs have
+    the left eye to the left, and cross-eyed pairs have the right eye to
+    the left. Anaglyphs are composed when both eyes are drawn for a pixel,
+    with the luminance of the left eye in the red channel, and the green
+    and blue channels of the right eye. Using the luminance for red gives
+    less rivalry between the eyes than the red channel alone.
+     */
+    pub fn draw_eye_pixel(&mut self, eye: Eye, x: u32, y: u32, color: Color) {
+	let width = CONFIG.width;
+
+	match (self.stereo, eye) {
+	    (Stereo::SideBySide, Eye::Right) | (Stereo::CrossEye, Eye::Left) => {
+		self.draw_pixel(x + width, y, color);
+	    },
+	    (Stereo::Anaglyph, Eye::Left | Eye::Right) => {
+		if self.left.is_empty() {
+		    let n = (width*CONFIG.height) as usize;
+		    self.left = vec![None; n];
+		    self.right = vec![None; n];
+		}
+
+		let i = (y*width + x) as usize;
+		if eye == Eye::Left {
+		    self.left[i] = Some(color);
+		}
+		else {
+		    self.right[i] = Some(color);
+		}
+
+		if let (Some(l), Some(r)) = (self.left[i], self.right[i]) {
+		    let [lr, lg, lb] = l.as_array();
+		    let [_, rg, rb] = r.as_array();
+		    let lum = 0.2126*lr + 0.7152*lg + 0.0722*lb;
 
-	    if y != self.last_y {
-		a.present();
-		self.last_y = y;
+		    self.draw_pixel(x, y, Color::new(lum, rg, rb));
+		}
+	    },
+	    _ => self.draw_pixel(x, y, color),
+	}
+    }
+
+    // Set the terrain of a pixel in the rasters, placed as in
+    // draw_eye_pixel(). Anaglyphs have the rasters of the left eye.
+    pub fn draw_eye_hit(&mut self, eye: Eye, x: u32, y: u32,
+			surface: Option<Surface>) {
+	if let Some(rasters) = self.rasters.as_mut() {
+	    match (self.stereo, eye) {
+		(Stereo::SideBySide, Eye::Right) | (Stereo::CrossEye, Eye::Left) =>
+		    rasters.set(x + CONFIG.width, y, surface),
+		(Stereo::Anaglyph, Eye::Right) => {},
+		_ => rasters.set(x, y, surface),
 	    }
 	}
     }
 
-    pub fn save(&self) {
+    // Show the pixels drawn so far. The render threads draw pixels from
+    // several strips at once, so we present when a strip is finished.
+    pub fn present(&mut self) {
+	if let Some(a) = self.canvas.as_mut() {
+	    a.present();
+	}
+    }
+
+    // Save the image. A cubemap is also saved as one image per face, with
+    // the name of the face added to the file name, e.g. out_front.tif. The
+    // rasters are saved the same way, e.g. out_depth.tif. Returns the names
+    // of the saved files.
+    pub fn save(&self) -> Vec<String> {
 	self.im.save(&CONFIG.output).unwrap();
+	let mut files = vec![CONFIG.output.clone()];
+
+	if self.kind == Kind::Cubemap &&
+	    self.stereo == Stereo::None {
+	    let s = self.im.height();
+
+	    for (i, name) in CUBE_FACES.iter().enumerate() {
+		let face_path = Canvas::output_path(name);
+
+		image::imageops::crop_imm(&self.im, (i as u32)*s, 0, s, s)
+		    .to_image()
+		    .save(&face_path)
+		    .unwrap();
+		files.push(face_path.to_string_lossy().to_string());
+	    }
+	}
+
+	if let Some(rasters) = &self.rasters {
+	    files.extend(rasters.save(
+		|name| Canvas::output_path(name).with_extension("tif")));
+	}
+
+	files
+    }
+
+    // Save a frame of an animation, with the frame number added to the
+    // file name, e.g. out_0001.tif, and its rasters, e.g.
+    // out_0001_depth.tif. Returns the name of the file. Frames of
+    // an animated GIF or PNG are kept until save_animation(). The next
+    // frame is drawn on top of this one.
+    pub fn save_frame(&mut self, frame: usize) -> Option<String> {
+	self.left.clear();
+	self.right.clear();
+
+	if let Some(rasters) = &self.rasters {
+	    rasters.save(|name| Canvas::output_path(&format!("{:04}_{}", frame, name))
+			 .with_extension("tif"));
+	}
+
+	if self.format != Format::Frames {
+	    self.frames.push(self.im.clone());
+	    return None;
+	}
+
+	let path = Canvas::output_path(&format!("{:04}", frame));
+	self.im.save(&path).unwrap();
+
+	Some(path.to_string_lossy().to_string())
+    }
+
+    // Save the frames of an animated GIF or PNG, with the output file name
+    // and .gif or .png extension. Returns the name of the file.
+    pub fn save_animation(&mut self) -> Option<String> {
+	let delay = CONFIG.frame_delay;
+	let path;
+
+	match self.format {
+	    Format::Frames => return None,
+	    Format::Gif => {
+		path = Path::new(&CONFIG.output).with_extension("gif");
+		let mut encoder = GifEncoder::new(File::create(&path).unwrap());
+		encoder.set_repeat(Repeat::Infinite).unwrap();
+
+		for im in self.frames.drain(..) {
+		    let rgba = DynamicImage::ImageRgb8(im).into_rgba8();
+		    encoder.encode_frame(Frame::from_parts(
+			rgba, 0, 0, Delay::from_numer_denom_ms(delay, 1))).unwrap();
+		}
+	    },
+	    Format::Apng => {
+		path = Path::new(&CONFIG.output).with_extension("png");
+		let file = BufWriter::new(File::create(&path).unwrap());
+		let mut encoder = png::Encoder::new(file, self.im.width(),
+						    self.im.height());
+		encoder.set_color(png::ColorType::Rgb);
+		encoder.set_depth(png::BitDepth::Eight);
+		encoder.set_animated(self.frames.len() as u32, 0).unwrap();
+		encoder.set_frame_delay(delay.min(u16::MAX as u32) as u16, 1000)
+		    .unwrap();
+
+		let mut writer = encoder.write_header().unwrap();
+		for im in self.frames.drain(..) {
+		    writer.write_image_data(im.as_raw()).unwrap();
+		}
+		writer.finish().unwrap();
+	    },
+	}
+
+	Some(path.to_string_lossy().to_string())
+    }
+
+    // Output file name with a suffix added, before the extension
+    fn output_path(suffix: &str) -> PathBuf {
+	let path = Path::new(&CONFIG.output);
+	let stem = path.file_stem().unwrap().to_string_lossy();
+	let mut file = format!("{}_{}", stem, suffix);
+
+	if let Some(ext) = path.extension() {
+	    file = format!("{}.{}", file, ext.to_string_lossy());
+	}
+
+	path.with_file_name(file)
     }
 
     pub fn finish_displayed_canvas(&mut self) {
@@ -1,6 +1,8 @@
 extern crate sdl2;
+use hoydedata::{Error, Result};
 use std::ops;
 
+#[derive(Clone, Copy)]
 pub struct Color {
     r: f32,
     g: f32,
@@ -8,6 +10,14 @@ pub struct Color {
 }
 
 impl Color {
+    pub const fn new(r: f32, g: f32, b: f32) -> Color {
+	Color { r, g, b }
+    }
+
+    pub fn as_array(&self) -> [f32; 3] {
+	[self.r, self.g, self.b]
+    }
+
     pub fn blend(&self, other: &Color, factor: f32) -> Color {
 	Color {
 	    r: self.r*(1.0 - factor) + other.r*factor,
@@ -16,13 +26,92 @@ impl Color {
 	}
     }
 
-    pub fn as_u8_array(&self) -> [u8; 3] {
-	[self.r as u8, self.g as u8, self.b as u8]
+    // Blend with separate factors for each color component
+    pub fn blend_color(&self, other: &Color, factor: Color) -> Color {
+	Color {
+	    r: self.r*(1.0 - factor.r) + other.r*factor.r,
+	    g: self.g*(1.0 - factor.g) + other.g*factor.g,
+	    b: self.b*(1.0 - factor.b) + other.b*factor.b,
+	}
+    }
+
+    // Encode linear color component to 8 bit sRGB. The dither value (in
+    // the range -1 to 1) is added before rounding, to avoid banding in
+    // smooth gradients.
+    fn encode(c: f32, dither: f32) -> u8 {
+	let c = c.clamp(0.0, 1.0);
+	let s = if c <= 0.0031308 {
+	    12.92*c
+	}
+	else {
+	    1.055*c.powf(1.0/2.4) - 0.055
+	};
+
+	(255.0*s + 0.5 + dither).clamp(0.0, 255.0) as u8
+    }
+
+    pub fn as_u8_array(&self, dither: [f32; 3]) -> [u8; 3] {
+	[Color::encode(self.r, dither[0]),
+	 Color::encode(self.g, dither[1]),
+	 Color::encode(self.b, dither[2])]
+    }
+
+    pub fn as_sdl2_color(&self, dither: [f32; 3]) -> sdl2::pixels::Color {
+	let [r, g, b] = self.as_u8_array(dither);
+	sdl2::pixels::Color::RGB(r, g, b)
+    }
+
+    // Difference between two colors, used for finding edges in the image.
+    // The components are compressed by c/(1 + c) first, so that the
+    // difference is comparable for bright and dark colors.
+    pub fn difference(&self, other: &Color) -> f32 {
+	let f = |a: f32, b: f32| (a/(1.0 + a) - b/(1.0 + b)).abs();
+
+	f(self.r, other.r).max(f(self.g, other.g)).max(f(self.b, other.b))
     }
 
-    pub fn as_sdl2_color(&self) -> sdl2::pixels::Color {
-        // Need to gamma-correct?
-	sdl2::pixels::Color::RGB(self.r as u8, self.g as u8, self.b as u8)
+    // Apply exposure and tone mapping, for displaying a high dynamic range
+    // color.
+    pub fn tone_map(&self, exposure: f32, tone_map: &ToneMap) -> Color {
+	let c = *self*exposure;
+
+	match tone_map {
+	    ToneMap::None => c,
+	    ToneMap::Reinhard => {
+		Color {
+		    r: c.r/(1.0 + c.r),
+		    g: c.g/(1.0 + c.g),
+		    b: c.b/(1.0 + c.b),
+		}
+	    },
+	    ToneMap::Aces => {
+		// Filmic curve fitted to ACES by Krzysztof Narkowicz
+		let f = |x: f32| {
+		    (x*(2.51*x + 0.03))/(x*(2.43*x + 0.59) + 0.14)
+		};
+		Color { r: f(c.r), g: f(c.g), b: f(c.b) }
+	    },
+	}
+    }
+}
+
+// Tone mapping operators, for compressing high dynamic range colors into
+// the displayable range.
+pub enum ToneMap {
+    None,
+    Reinhard,
+    Aces,
+}
+
+impl ToneMap {
+    pub fn from(name: &str) -> Result<ToneMap> {
+	match name {
+	    "none" => Ok(ToneMap::None),
+	    "reinhard" => Ok(ToneMap::Reinhard),
+	    "aces" => Ok(ToneMap::Aces),
+	    _ => Err(Error::Generic(
+		format!("Unknown tone mapping operator {}", name))),
+	}
     }
 }
 
@@ -42,6 +131,15 @@ impl ops::Add<Color> for Color {
     }
 }
 
+// Component-wise product, e.g. for filtering light through a medium
+impl ops::Mul<Color> for Color {
+    type Output = Color;
+
+    fn mul(self, _rhs: Color) -> Color {
+	Color { r: self.r*_rhs.r, g: self.g*_rhs.g, b: self.b*_rhs.b }
+    }
+}
+
 impl ops::Mul<f32> for Color {
     type Output = Color;
 
@@ -50,14 +148,18 @@ impl ops::Mul<f32> for Color {
     }
 }
 
-pub const SNOW_DARK: Color = Color { r: 10.0, g: 60.0, b: 80.0 };
-pub const SNOW: Color = Color { r: 255.0, g: 255.0, b: 255.0 };
+// All colors are linear (not gamma encoded), with 1 being white in full
+// daylight. The comments show the sRGB values.
+pub const SNOW_DARK: Color = Color { r: 0.0030, g: 0.0452, b: 0.0802 }; // 10, 60, 80
+pub const SNOW: Color = Color { r: 1.0, g: 1.0, b: 1.0 };
 pub const LAND_DARK: Color = Color { r: 0.0, g: 0.0, b: 0.0 };
-pub const ROCK: Color = Color { r: 134.0, g: 138.0, b: 103.0 };
-pub const FOREST: Color = Color { r: 122.0, g: 132.0, b: 0.0 };
-pub const SEA: Color = Color { r: 0.0, g: 42.0, b: 72.0 };
-pub const LAND_BLUE: Color = Color { r: 176.0, g: 215.0, b: 253.0 };
+pub const ROCK: Color = Color { r: 0.2384, g: 0.2542, b: 0.1356 }; // 134, 138, 103
+pub const FOREST: Color = Color { r: 0.1946, g: 0.2307, b: 0.0 }; // 122, 132, 0
+pub const SEA: Color = Color { r: 0.0, g: 0.0232, b: 0.0648 }; // 0, 42, 72
+pub const SEA_SHALLOW: Color = Color { r: 0.0070, g: 0.1559, b: 0.1878 }; // 20, 110, 120
+pub const FJORD: Color = Color { r: 0.0030, g: 0.0262, b: 0.0319 }; // 10, 45, 50
+pub const FJORD_SHALLOW: Color = Color { r: 0.0212, g: 0.0802, b: 0.0452 }; // 40, 80, 60
+pub const GLACIAL: Color = Color { r: 0.0212, g: 0.1878, b: 0.2051 }; // 40, 120, 125
+pub const GLACIAL_SHALLOW: Color = Color { r: 0.1878, g: 0.4020, b: 0.3515 }; // 120, 170, 160
 pub const BLACK: Color = Color { r: 0.0, g: 0.0, b: 0.0 };
-pub const WHITE: Color = Color { r: 255.0, g: 255.0, b: 255.0 };
-pub const DARK_SKY_BLUE: Color = Color { r: 119.0, g: 181.0, b: 254.0 };
-pub const LIGHT_SKY_BLUE: Color = Color { r: 233.0, g: 249.0, b: 255.0 };
+pub const WHITE: Color = Color { r: 1.0, g: 1.0, b: 1.0 };
@@ -29,11 +29,63 @@ pub struct Config {
     pub time: String,
     pub output: String,
     pub headless: bool,
+    pub threads: u32,
+    pub pyramid: bool,
+    pub shadows: bool,
+    pub shadow_softness: f32,
+    pub shadow_samples: u16,
+    pub ambient_light: f32,
+    pub exposure: f32,
+    pub tonemap: String,
+    pub samples: u16,
+    pub sampling: String,
+    pub adaptive: bool,
+    pub adaptive_color: f32,
+    pub adaptive_depth: f32,
+    pub atlas_footprint: f32,
+    pub atlas_blend: f32,
+    pub projection: String,
+    pub focal_length: f32,
+    pub sensor_width: f32,
+    pub lens_shift_x: f32,
+    pub lens_shift_y: f32,
+    pub mask_angle: f32,
+    pub stereo: String,
+    pub stereo_baseline: f32,
+    pub keyframes: String,
+    pub gpx: String,
+    pub frames: u32,
+    pub gpx_lookahead: f32,
+    pub time_start: String,
+    pub time_end: String,
+    pub time_step: f32,
+    pub animation_format: String,
+    pub frame_delay: u32,
+    pub gbuffer: String,
+    pub rasters: String,
+    pub nodata: f32,
+    pub water_mask: String,
+    pub lake_detection: bool,
+    pub lake_radius: f32,
+    pub lake_tolerance: f32,
+    pub water_type: String,
+    pub lake_type: String,
+    pub bathymetry: String,
+    pub shore_distance: f32,
+    pub wind_direction: f32,
+    pub seed: u64,
+    pub azimuth: Option<f32>,
+    pub pitch: Option<f32>,
+    pub roll: Option<f32>,
+    pub wind_speed: Option<f32>,
 }
 
 // FIXME: Change this to a simple const which is initialized first with standard values,
 // then initialized from file.
 
+// UTM zone of the maps, and of the coordinates in config
+pub const UTM_ZONE: u8 = 33;
+
 lazy_static! {
     pub static ref CONFIG: Config = Config::new();
 }
@@ -95,6 +147,51 @@ impl Config {
 		("time", "2023-07-01T18:00:00+0200"),
 		("output", "out.tif"),
 		("headless", "false"),
+		("threads", "0"),
+		("pyramid", "false"),
+		("shadows", "false"),
+		("shadow_softness", "0"),
+		("shadow_samples", "8"),
+		("ambient_light", "0.1"),
+		("exposure", "0"),
+		("tonemap", "none"),
+		("samples", "1"),
+		("sampling", "stratified"),
+		("adaptive", "false"),
+		("adaptive_color", "0.05"),
+		("adaptive_depth", "0.1"),
+		("atlas_footprint", "8"),
+		("atlas_blend", "0.25"),
+		("projection", "perspective"),
+		("focal_length", "50"),
+		("sensor_width", "36"),
+		("lens_shift_x", "0"),
+		("lens_shift_y", "0"),
+		("mask_angle", "0"),
+		("stereo", "none"),
+		("stereo_baseline", "100"),
+		("keyframes", ""),
+		("gpx", ""),
+		("frames", "100"),
+		("gpx_lookahead", "500"),
+		("time_start", ""),
+		("time_end", ""),
+		("time_step", "10"),
+		("animation_format", "frames"),
+		("frame_delay", "100"),
+		("gbuffer", ""),
+		("rasters", ""),
+		("nodata", "-9999"),
+		("water_mask", ""),
+		("lake_detection", "true"),
+		("lake_radius", "30"),
+		("lake_tolerance", "0.1"),
+		("water_type", "sea"),
+		("lake_type", "sea"),
+		("bathymetry", ""),
+		("shore_distance", "0"),
+		("wind_direction", "0"),
+		("seed", "0"),
 	    ]);
 	builder.add(Box::new(ini_src));
 	// builder.add_env_vars();
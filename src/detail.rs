@@ -0,0 +1,274 @@
+// Heights of the 1m maps near the observer, shared by the render threads
+use hoydedata::{Atlas, Coord, Error, Result};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use std::thread::spawn;
+
+// Side of the blocks of heights (meters). A block has a sample every meter,
+// including both edges, so that any point in the block can be interpolated.
+const BLOCK: i32 = 250;
+const SIDE: usize = (BLOCK + 1) as usize;
+
+// Block with the heights of a square, row by row from the south west corner
+type Block = Vec<f32>;
+
+// South west corner of a block, in units of BLOCK
+type Key = (i32, i32);
+
+/*
+Heights of the 1m maps in the part of the terrain where the renderer uses
+them, looked up before rendering starts. The heights are kept in square
+blocks, and interpolated between the samples. Since the heights do not
+depend on which maps a thread has loaded, a ray sees the same terrain
+whichever thread traces it, and a render with several threads is the same
+as with one. Blocks outside the 1m maps have no heights, and the renderer
+uses the 10m maps there.
+ */
+pub struct Detail {
+    blocks: HashMap<Key, Option<Arc<Block>>>,
+}
+
+impl Detail {
+    /*
+    Blocks within dist from the observer, in the directions from
+    h_middle - half_angle to h_middle + half_angle. The margin is added to
+    the distance and to the sides of the sector, e.g. for the eyes of a
+    stereo pair. A block is identified by its south west corner, in units
+    of BLOCK.
+     */
+    pub fn blocks(observer: Coord, h_middle: f32, half_angle: f32, dist: f32,
+		  margin: f32) -> Vec<Key> {
+	let radius = 0.5*(BLOCK as f32)*2.0_f32.sqrt();
+	let reach = dist + margin + radius;
+	let b = BLOCK as f32;
+	let mut res = Vec::new();
+
+	let first = |x: f32| ((x - reach)/b).floor() as i32;
+	let last = |x: f32| ((x + reach)/b).floor() as i32;
+
+	for bn in first(observer.n)..=last(observer.n) {
+	    for be in first(observer.e)..=last(observer.e) {
+		let de = ((be as f32) + 0.5)*b - observer.e;
+		let dn = ((bn as f32) + 0.5)*b - observer.n;
+		let d = (de*de + dn*dn).sqrt();
+
+		if d > reach {
+		    continue;
+		}
+
+		// Angle from the middle of the sector, and the angle the block
+		// and the margin cover seen from the observer
+		if half_angle < PI && d > radius + margin {
+		    let a = (dn.atan2(de) - h_middle + PI).rem_euclid(2.0*PI) - PI;
+		    if a.abs() > half_angle + ((radius + margin)/d).asin() {
+			continue;
+		    }
+		}
+
+		res.push((be, bn));
+	    }
+	}
+
+	res
+    }
+
+    // Look up the heights of the blocks by a number of threads, each with
+    // its own atlas. The blocks which are in previous are taken from there.
+    pub fn build(keys: &[Key], previous: Option<&Detail>, threads: usize)
+		 -> Result<Self> {
+	let mut blocks = HashMap::new();
+	let mut missing = Vec::new();
+
+	for key in keys {
+	    match previous.and_then(|p| p.blocks.get(key)) {
+		Some(block) => {
+		    blocks.insert(*key, block.clone());
+		},
+		None => missing.push(*key),
+	    }
+	}
+
+	// Neighbour blocks are usually in the same map, so each thread takes
+	// a run of blocks, row by row.
+	missing.sort_by_key(|(e, n)| (*n, *e));
+	let chunk = missing.len().div_ceil(threads.max(1)).max(1);
+	let mut workers = Vec::new();
+
+	for keys in missing.chunks(chunk) {
+	    let keys = keys.to_vec();
+	    workers.push(spawn(move || {
+		Detail::build_blocks(keys).map_err(|e| e.to_string())
+	    }));
+	}
+
+	for w in workers {
+	    match w.join().unwrap() {
+		Ok(res) => {
+		    for (key, block) in res {
+			blocks.insert(key, block.map(Arc::new));
+		    }
+		},
+		Err(e) => {
+		    return Err(Error::Generic(e));
+		}
+	    }
+	}
+
+	Ok(Self { blocks })
+    }
+
+    fn build_blocks(keys: Vec<Key>) -> Result<Vec<(Key, Option<Block>)>> {
+	let mut atlas1 = Atlas::new(1.0, None)?;
+	let mut res = Vec::new();
+
+	for key in keys {
+	    let block = Detail::sample(key, |c| {
+		if !atlas1.has_maps(c) {
+		    return None;
+		}
+		if !atlas1.has_images(c) && atlas1.load_images(c).is_err() {
+		    return None;
+		}
+		atlas1.lookup(c).ok()
+	    });
+	    res.push((key, block));
+	}
+
+	Ok(res)
+    }
+
+    // Sample the heights of a block, NaN where there are none. None if
+    // there are no heights in the whole block.
+    fn sample(key: Key, mut lookup: impl FnMut(&Coord) -> Option<f32>)
+	      -> Option<Block> {
+	let mut c = Coord::from_polar(0.0, 0.0);
+	let mut block = vec![f32::NAN; SIDE*SIDE];
+	let mut found = false;
+
+	for (i, h) in block.iter_mut().enumerate() {
+	    c.e = (key.0*BLOCK + (i % SIDE) as i32) as f32;
+	    c.n = (key.1*BLOCK + (i/SIDE) as i32) as f32;
+
+	    if let Some(height) = lookup(&c) {
+		*h = height;
+		found = true;
+	    }
+	}
+
+	if found { Some(block) } else { None }
+    }
+
+    // Heights given by a function, for the tests
+    #[cfg(test)]
+    pub fn from_fn(keys: &[Key], f: impl Fn(&Coord) -> f32) -> Self {
+	let blocks = keys.iter()
+	    .map(|key| (*key, Detail::sample(*key, |c| Some(f(c))).map(Arc::new)))
+	    .collect();
+
+	Self { blocks }
+    }
+
+    // Check if all the blocks are looked up
+    pub fn covers(&self, keys: &[Key]) -> bool {
+	keys.iter().all(|key| self.blocks.contains_key(key))
+    }
+
+    // Heights at the corners of the sample cell of a coordinate, and the
+    // position within the cell
+    fn corners(&self, c: &Coord) -> Option<([f32; 4], f32, f32)> {
+	let b = BLOCK as f32;
+	let be = (c.e/b).floor() as i32;
+	let bn = (c.n/b).floor() as i32;
+	let block = self.blocks.get(&(be, bn))?.as_ref()?;
+
+	let fe = c.e - (be*BLOCK) as f32;
+	let fn_ = c.n - (bn*BLOCK) as f32;
+	let col = (fe as usize).min(SIDE - 2);
+	let row = (fn_ as usize).min(SIDE - 2);
+	let i = row*SIDE + col;
+	let h = [block[i], block[i + 1], block[i + SIDE], block[i + SIDE + 1]];
+
+	if h.iter().any(|v| v.is_nan()) {
+	    return None;
+	}
+
+	Some((h, fe - col as f32, fn_ - row as f32))
+    }
+
+    // Terrain height at a coordinate, by bilinear interpolation. None
+    // outside the blocks and the 1m maps.
+    pub fn lookup(&self, c: &Coord) -> Option<f32> {
+	let ([h00, h10, h01, h11], u, v) = self.corners(c)?;
+
+	Some((h00*(1.0 - u) + h10*u)*(1.0 - v) + (h01*(1.0 - u) + h11*u)*v)
+    }
+
+    // Terrain height and gradient (east, north) at a coordinate
+    pub fn lookup_with_gradient(&self, c: &Coord) -> Option<(f32, f32, f32)> {
+	let ([h00, h10, h01, h11], u, v) = self.corners(c)?;
+	let h = (h00*(1.0 - u) + h10*u)*(1.0 - v) + (h01*(1.0 - u) + h11*u)*v;
+	let dx = (h10 - h00)*(1.0 - v) + (h11 - h01)*v;
+	let dy = (h01 - h00)*(1.0 - u) + (h11 - h10)*u;
+
+	Some((h, dx, dy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coord(e: f32, n: f32) -> Coord {
+	let mut c = Coord::from_polar(0.0, 0.0);
+	c.e = e;
+	c.n = n;
+	c
+    }
+
+    #[test]
+    fn plane_is_interpolated_exactly() {
+	let plane = |c: &Coord| 0.5*(c.e - 100000.0) - 0.25*(c.n - 6900000.0);
+	let detail = Detail::from_fn(&[(400, 27600), (401, 27600)], plane);
+
+	for c in [coord(100000.0, 6900000.0), coord(100123.5, 6900017.5),
+		  coord(100249.5, 6900249.5), coord(100250.0, 6900100.0)] {
+	    let (h, dx, dy) = detail.lookup_with_gradient(&c).unwrap();
+	    assert!((h - plane(&c)).abs() < 1e-3);
+	    assert_eq!((dx, dy), (0.5, -0.25));
+	}
+    }
+
+    #[test]
+    fn no_heights_outside_blocks() {
+	let detail = Detail::from_fn(&[(400, 27600)], |_| 10.0);
+
+	assert_eq!(detail.lookup(&coord(100010.0, 6900010.0)), Some(10.0));
+	assert_eq!(detail.lookup(&coord(99990.0, 6900010.0)), None);
+	assert_eq!(detail.lookup(&coord(100260.0, 6900010.0)), None);
+	assert!(detail.covers(&[(400, 27600)]));
+	assert!(!detail.covers(&[(400, 27600), (401, 27600)]));
+    }
+
+    #[test]
+    fn blocks_cover_sector() {
+	// In the middle of a block, looking east, 90 degrees wide, out to
+	// 1000m
+	let o = coord(100125.0, 6900125.0);
+	let keys = Detail::blocks(o, 0.0, 0.25*PI, 1000.0, 0.0);
+
+	// The block of the observer, and the blocks in front
+	for e in 400..405 {
+	    assert!(keys.contains(&(e, 27600)));
+	}
+	// Nothing behind, to the side or beyond
+	assert!(!keys.contains(&(399, 27600)));
+	assert!(!keys.contains(&(400, 27604)));
+	assert!(!keys.contains(&(406, 27600)));
+
+	// A full circle has the blocks behind too
+	let all = Detail::blocks(o, 0.0, PI, 1000.0, 0.0);
+	assert!(all.contains(&(398, 27600)));
+	assert!(all.contains(&(400, 27604)));
+    }
+}
@@ -0,0 +1,392 @@
+// Geometry buffer of a rendered image, for shading it again with other light
+use crate::config::CONFIG;
+use crate::stereo::Eye;
+use crate::view::View;
+
+use hoydedata::{Coord, Error, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+// File type and version, at the start of the file
+const MAGIC: &[u8; 4] = b"GNGB";
+const VERSION: u32 = 2;
+
+// Where a ray hit the terrain: the coordinate, the distance from the start
+// of the ray, and the height and gradient of the terrain. On water, we
+// have the height of the water surface.
+#[derive(Clone, Copy)]
+pub struct Hit {
+    pub coord: Coord,
+    pub dist: f32,
+    pub height: f32,
+    pub dhx: f32,
+    pub dhy: f32,
+    pub water: Option<f32>,
+}
+
+// A traced sample: the view direction of the ray, and where it hit the
+// terrain (None for sky). The sample can be shaded again with another
+// light, without tracing the ray.
+#[derive(Clone, Copy)]
+pub struct Sample {
+    pub h_angle: f32,
+    pub v_angle: f32,
+    pub hit: Option<Hit>,
+}
+
+// Traced samples of a pixel. The pixel color is the sum of the sample
+// colors times scale.
+#[derive(Clone, Default)]
+pub struct Hits {
+    pub scale: f32,
+    pub samples: Vec<Sample>,
+}
+
+impl Hits {
+    // The terrain seen in the pixel: the sample with the nearest hit, or
+    // None when most of the samples hit the sky.
+    pub fn surface(&self) -> Option<&Sample> {
+	let land: Vec<&Sample> = self.samples.iter().filter(|s| s.hit.is_some())
+	    .collect();
+	if land.is_empty() || 2*land.len() < self.samples.len() {
+	    return None;
+	}
+
+	land.into_iter().min_by(|a, b| {
+	    a.hit.unwrap().dist.total_cmp(&b.hit.unwrap().dist)
+	})
+    }
+}
+
+// Traced samples of each vertical strip of the image, by eye and start
+// column. The pixels of a strip are ordered column by column.
+pub type Strips = HashMap<(Eye, u32), Vec<Hits>>;
+
+/*
+Geometry buffer (G-buffer) of an image: the view, and the traced samples of
+each pixel with the terrain they hit. The buffer holds no colors, so the
+image can be shaded from it with another time, haziness, snow limit or
+palette, without tracing the view rays again.
+
+The file format is little endian binary: a header with the image size and
+the view, followed by the strips.
+ */
+pub struct GBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub view: View,
+    pub strips: Strips,
+}
+
+fn write_u32(w: &mut impl Write, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_f32(w: &mut impl Write, v: f32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_coord(w: &mut impl Write, c: Coord) -> io::Result<()> {
+    write_f32(w, c.e)?;
+    write_f32(w, c.n)
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_f32(r: &mut impl Read) -> io::Result<f32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(f32::from_le_bytes(b))
+}
+
+// Coordinates are made from the given origin, e.g. the observer in config,
+// which gives them the same map projection.
+fn read_coord(r: &mut impl Read, origin: Coord) -> io::Result<Coord> {
+    let mut c = origin;
+    c.e = read_f32(r)?;
+    c.n = read_f32(r)?;
+    Ok(c)
+}
+
+fn eye_from_code(code: u32) -> io::Result<Eye> {
+    match code {
+	0 => Ok(Eye::Centre),
+	1 => Ok(Eye::Left),
+	2 => Ok(Eye::Right),
+	_ => Err(io::Error::new(io::ErrorKind::InvalidData, "Bad eye")),
+    }
+}
+
+impl GBuffer {
+    pub fn save(&self, file: &str) -> Result<()> {
+	File::create(file)
+	    .and_then(|f| self.write(&mut BufWriter::new(f)))
+	    .map_err(|e| Error::Generic(format!("{}: {}", file, e)))
+    }
+
+    pub fn load(file: &str) -> Result<GBuffer> {
+	File::open(file)
+	    .and_then(|f| GBuffer::read(&mut BufReader::new(f), CONFIG.observer))
+	    .map_err(|e| Error::Generic(format!("{}: {}", file, e)))
+    }
+
+    fn write(&self, w: &mut impl Write) -> io::Result<()> {
+	w.write_all(MAGIC)?;
+	write_u32(w, VERSION)?;
+	write_u32(w, self.width)?;
+	write_u32(w, self.height)?;
+
+	// The view. A missing observer height is written as NaN.
+	let view = &self.view;
+	write_coord(w, view.observer)?;
+	write_coord(w, view.target)?;
+	write_f32(w, view.observer_height.unwrap_or(f32::NAN))?;
+	write_f32(w, view.width_angle)?;
+	write_u32(w, view.time.len() as u32)?;
+	w.write_all(view.time.as_bytes())?;
+
+	// The strips are written in order of eye and column, so that the
+	// same render gives the same file
+	let mut keys: Vec<&(Eye, u32)> = self.strips.keys().collect();
+	keys.sort_by_key(|(eye, x)| (eye.code(), *x));
+
+	write_u32(w, self.strips.len() as u32)?;
+	for key in keys {
+	    let (eye, x) = *key;
+	    let pixels = &self.strips[key];
+	    write_u32(w, eye.code())?;
+	    write_u32(w, x)?;
+	    write_u32(w, pixels.len() as u32)?;
+
+	    for hits in pixels {
+		write_f32(w, hits.scale)?;
+		write_u32(w, hits.samples.len() as u32)?;
+
+		for sample in &hits.samples {
+		    write_f32(w, sample.h_angle)?;
+		    write_f32(w, sample.v_angle)?;
+
+		    match &sample.hit {
+			None => write_u32(w, 0)?,
+			Some(hit) => {
+			    // Land is 1, water 2, followed by the height of the
+			    // water surface
+			    write_u32(w, if hit.water.is_some() { 2 } else { 1 })?;
+			    write_coord(w, hit.coord)?;
+			    write_f32(w, hit.dist)?;
+			    write_f32(w, hit.height)?;
+			    write_f32(w, hit.dhx)?;
+			    write_f32(w, hit.dhy)?;
+			    if let Some(level) = hit.water {
+				write_f32(w, level)?;
+			    }
+			},
+		    }
+		}
+	    }
+	}
+
+	w.flush()
+    }
+
+    // Read a geometry buffer, with coordinates made from origin
+    fn read(r: &mut impl Read, origin: Coord) -> io::Result<GBuffer> {
+	let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+	let mut magic = [0u8; 4];
+	r.read_exact(&mut magic)?;
+	if &magic != MAGIC || read_u32(r)? != VERSION {
+	    return Err(bad("Not a geometry buffer, or wrong version"));
+	}
+
+	let width = read_u32(r)?;
+	let height = read_u32(r)?;
+
+	let observer = read_coord(r, origin)?;
+	let target = read_coord(r, origin)?;
+	let observer_height = read_f32(r)?;
+	let width_angle = read_f32(r)?;
+	let mut time = vec![0u8; read_u32(r)? as usize];
+	r.read_exact(&mut time)?;
+
+	let view = View {
+	    observer,
+	    target,
+	    observer_height: if observer_height.is_nan() { None } else { Some(observer_height) },
+	    time: String::from_utf8(time).map_err(|_| bad("Bad time"))?,
+	    width_angle,
+	};
+
+	let mut strips = HashMap::new();
+	for _ in 0..read_u32(r)? {
+	    let eye = eye_from_code(read_u32(r)?)?;
+	    let x = read_u32(r)?;
+	    let n_pixels = read_u32(r)? as usize;
+	    let mut pixels = Vec::with_capacity(n_pixels);
+
+	    for _ in 0..n_pixels {
+		let scale = read_f32(r)?;
+		let n_samples = read_u32(r)? as usize;
+		let mut samples = Vec::with_capacity(n_samples);
+
+		for _ in 0..n_samples {
+		    let h_angle = read_f32(r)?;
+		    let v_angle = read_f32(r)?;
+		    let mut hit = None;
+
+		    let kind = read_u32(r)?;
+		    if kind > 2 {
+			return Err(bad("Bad sample"));
+		    }
+
+		    if kind > 0 {
+			hit = Some(Hit {
+			    coord: read_coord(r, origin)?,
+			    dist: read_f32(r)?,
+			    height: read_f32(r)?,
+			    dhx: read_f32(r)?,
+			    dhy: read_f32(r)?,
+			    water: if kind == 2 { Some(read_f32(r)?) } else { None },
+			});
+		    }
+
+		    samples.push(Sample {
+			h_angle,
+			v_angle,
+			hit,
+		    });
+		}
+
+		pixels.push(Hits {
+		    scale,
+		    samples,
+		});
+	    }
+
+	    strips.insert((eye, x), pixels);
+	}
+
+	Ok(GBuffer {
+	    width,
+	    height,
+	    view,
+	    strips,
+	})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coord(e: f32, n: f32) -> Coord {
+	let mut c = Coord::from_polar(0.0, 0.0);
+	c.e = e;
+	c.n = n;
+	c
+    }
+
+    fn sample(h_angle: f32, hit: Option<Hit>) -> Sample {
+	Sample { h_angle, v_angle: -0.01, hit }
+    }
+
+    fn gbuffer(order: &[(Eye, u32)]) -> GBuffer {
+	let land = Hit {
+	    coord: coord(101234.5, 6901234.5),
+	    dist: 5000.0,
+	    height: 812.25,
+	    dhx: 0.1,
+	    dhy: -0.2,
+	    water: None,
+	};
+	let water = Hit { water: Some(800.0), ..land };
+
+	let mut strips = HashMap::new();
+	for key in order {
+	    let h = (key.1 + key.0.code()) as f32;
+	    strips.insert(*key, vec![
+		Hits {
+		    scale: 0.5,
+		    samples: vec![sample(h, None), sample(h, Some(land))],
+		},
+		Hits { scale: 1.0, samples: vec![sample(h, Some(water))] },
+	    ]);
+	}
+
+	GBuffer {
+	    width: 2,
+	    height: 1,
+	    view: View {
+		observer: coord(100000.0, 6900000.0),
+		target: coord(110000.0, 6910000.0),
+		observer_height: None,
+		time: "2024-06-21T12:00:00+0200".to_string(),
+		width_angle: 0.6,
+	    },
+	    strips,
+	}
+    }
+
+    fn bytes(g: &GBuffer) -> Vec<u8> {
+	let mut w = Vec::new();
+	g.write(&mut w).unwrap();
+	w
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+	let g = gbuffer(&[(Eye::Left, 0), (Eye::Right, 0)]);
+	let data = bytes(&g);
+	let r = GBuffer::read(&mut data.as_slice(), coord(0.0, 0.0)).unwrap();
+
+	assert_eq!((r.width, r.height), (2, 1));
+	assert_eq!(r.view.time, g.view.time);
+	assert_eq!(r.view.observer_height, None);
+	assert_eq!(r.view.width_angle, 0.6);
+	assert_eq!(r.view.target.n, g.view.target.n);
+	assert_eq!(r.strips.len(), 2);
+
+	for (key, pixels) in &g.strips {
+	    let read = &r.strips[key];
+	    assert_eq!(read.len(), pixels.len());
+
+	    for (a, b) in pixels.iter().zip(read) {
+		assert_eq!(a.scale, b.scale);
+		assert_eq!(a.samples.len(), b.samples.len());
+
+		for (s, t) in a.samples.iter().zip(&b.samples) {
+		    assert_eq!(s.h_angle, t.h_angle);
+		    assert_eq!(s.v_angle, t.v_angle);
+		    assert_eq!(s.hit.is_some(), t.hit.is_some());
+
+		    if let (Some(h), Some(i)) = (s.hit, t.hit) {
+			assert_eq!(h.coord.e, i.coord.e);
+			assert_eq!(h.coord.n, i.coord.n);
+			assert_eq!((h.dist, h.height, h.dhx, h.dhy),
+				   (i.dist, i.height, i.dhx, i.dhy));
+			assert_eq!(h.water, i.water);
+		    }
+		}
+	    }
+	}
+    }
+
+    #[test]
+    fn same_file_whatever_the_order() {
+	let a = gbuffer(&[(Eye::Left, 0), (Eye::Left, 16), (Eye::Right, 0)]);
+	let b = gbuffer(&[(Eye::Right, 0), (Eye::Left, 16), (Eye::Left, 0)]);
+
+	assert_eq!(bytes(&a), bytes(&b));
+    }
+
+    #[test]
+    fn not_a_gbuffer() {
+	let data = b"GNGX\x02\x00\x00\x00".to_vec();
+	assert!(GBuffer::read(&mut data.as_slice(), coord(0.0, 0.0)).is_err());
+    }
+}
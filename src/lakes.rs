@@ -0,0 +1,428 @@
+use crate::config::CONFIG;
+use crate::water::WaterMask;
+
+use hoydedata::{Atlas, Coord, Error, Result};
+use std::f32::consts::PI;
+use std::sync::Arc;
+use std::thread::spawn;
+
+// Size of the cells of the lake grid (meters), as the 10m maps. Over large
+// views, the cells are made larger to keep the grid within LAKE_MAX_CELLS
+// in each direction.
+const LAKE_CELL: f32 = 10.0;
+const LAKE_MAX_CELLS: f32 = 4096.0;
+
+// Largest squared gradient of a lake surface in the maps
+pub const LAKE_MAX_GRADIENT: f32 = 0.0001;
+
+// Neighbour cells of a cell, by column and row
+const NEIGHBOURS: [(isize, isize); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0),
+					 (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+// Heights of the cells on lakes in a row of the grid, and which cells are
+// on land
+type Row = (usize, Vec<f32>, Vec<bool>);
+
+// A grid of square cells, from the corner (e0, n0)
+#[derive(Clone, Copy)]
+struct Grid {
+    e0: f32,
+    n0: f32,
+    cell: f32,
+    cols: usize,
+    rows: usize,
+}
+
+impl Grid {
+    // Index of the cell at a column and row, or None outside the grid
+    fn index(&self, col: isize, row: isize) -> Option<usize> {
+	if col < 0 || row < 0 ||
+	    col >= self.cols as isize || row >= self.rows as isize {
+	    return None;
+	}
+
+	Some((row as usize)*self.cols + col as usize)
+    }
+}
+
+/*
+Lakes in a rectangle around the rendered view, as connected regions of
+cells on a grid. With a water mask, the water cells of the mask make the
+regions, and neighbour cells belong to the same lake whatever their height
+in the maps. Without a mask, the cells where the maps are flat make the
+regions: the terrain has the same height within lake_tolerance all around
+the middle of the cell at lake_radius. Neighbour cells belong to the same
+lake if their height is within lake_tolerance of the first cell of the
+lake, so a gentle slope does not join lakes at different heights.
+
+Each lake has one surface height: the median height of its cells, which is
+robust against a few cells of shore. A lake which reaches water_level is
+part of the sea.
+
+With shore_distance, the distance from each cell to the nearest land is
+also found, for the depth of the water near the shore. Land is the cells
+above water_level which are not on a lake.
+ */
+pub struct Lakes {
+    grid: Grid,
+    // Lake number of each cell, starting at 1. 0 is no lake.
+    labels: Vec<u32>,
+    // Surface height of each lake
+    levels: Vec<f32>,
+    // Distance (meters) from each cell to land. Empty without
+    // shore_distance.
+    shore: Vec<f32>,
+}
+
+impl Lakes {
+    // Find the lakes in the rectangle from c_min to c_max. The height of
+    // each cell is found by a number of threads, each with its own atlas.
+    // Without a mask, the lakes are only found with lake_detection.
+    pub fn build(c_min: Coord, c_max: Coord, mask: Option<Arc<WaterMask>>,
+		 threads: usize) -> Result<Self> {
+	let cell = ((c_max.e - c_min.e).max(c_max.n - c_min.n)/LAKE_MAX_CELLS)
+	    .max(LAKE_CELL);
+	let e0 = (c_min.e/cell).floor()*cell;
+	let n0 = (c_min.n/cell).floor()*cell;
+	let grid = Grid {
+	    e0,
+	    n0,
+	    cell,
+	    cols: ((c_max.e - e0)/cell).ceil().max(1.0) as usize,
+	    rows: ((c_max.n - n0)/cell).ceil().max(1.0) as usize,
+	};
+
+	let mut workers = Vec::new();
+	for t in 0..threads {
+	    let mask = mask.clone();
+	    workers.push(spawn(move || {
+		Lakes::build_rows(c_min, grid, mask, t, threads)
+		    .map_err(|e| e.to_string())
+	    }));
+	}
+
+	// Height of the cells on lakes, NaN elsewhere, and which cells are
+	// on land
+	let cols = grid.cols;
+	let mut heights = vec![f32::NAN; cols*grid.rows];
+	let mut land = vec![false; cols*grid.rows];
+	for w in workers {
+	    match w.join().unwrap() {
+		Ok(res) => {
+		    for (row, v, l) in res {
+			heights[row*cols..(row + 1)*cols].copy_from_slice(&v);
+			land[row*cols..(row + 1)*cols].copy_from_slice(&l);
+		    }
+		},
+		Err(e) => {
+		    return Err(Error::Generic(e));
+		}
+	    }
+	}
+
+	// With a mask, all neighbour cells on water are connected
+	let tolerance = match mask {
+	    Some(_) => None,
+	    None => Some(CONFIG.lake_tolerance),
+	};
+	let (labels, levels) = label(&grid, &heights, tolerance, CONFIG.water_level);
+
+	let mut shore = Vec::new();
+	if CONFIG.shore_distance > 0.0 {
+	    for (l, label) in land.iter_mut().zip(&labels) {
+		*l = *l && *label == 0;
+	    }
+	    shore = shore_distances(&grid, &land);
+	}
+
+	Ok(Self { grid, labels, levels, shore })
+    }
+
+    // Find the height of every n'th row of cells on lakes, starting at row
+    // t, and which cells are above water_level. With a mask, the cells of
+    // the mask are on lakes. Outside the maps, we assume sea level. Without
+    // a mask, the cells where the maps are flat are on lakes.
+    fn build_rows(mut c: Coord, grid: Grid, mask: Option<Arc<WaterMask>>,
+		  t: usize, n: usize) -> Result<Vec<Row>> {
+	let atlas10 = Atlas::new(10.0, None)?;
+	let mut res = Vec::new();
+
+	for row in (t..grid.rows).step_by(n) {
+	    let mut v = vec![f32::NAN; grid.cols];
+	    let mut land = vec![false; grid.cols];
+
+	    for (col, (h, l)) in v.iter_mut().zip(land.iter_mut()).enumerate() {
+		c.e = grid.e0 + (col as f32 + 0.5)*grid.cell;
+		c.n = grid.n0 + (row as f32 + 0.5)*grid.cell;
+
+		if let Some(m) = &mask {
+		    let height = atlas10.lookup(&c).unwrap_or(0.0);
+		    if m.is_water(&c) {
+			*h = height;
+		    }
+		    *l = height > CONFIG.water_level;
+		    continue;
+		}
+
+		let (height, dhx, dhy) = match atlas10.lookup_with_gradient(&c) {
+		    Ok(t) => t,
+		    Err(_) => continue,
+		};
+		*l = height > CONFIG.water_level;
+
+		if !CONFIG.lake_detection ||
+		    dhx*dhx + dhy*dhy > LAKE_MAX_GRADIENT {
+		    continue;
+		}
+
+		let flat = (0..8).all(|i| {
+		    let p = Coord::from_polar(CONFIG.lake_radius, (i as f32)*0.25*PI) + c;
+		    atlas10.lookup(&p)
+			.is_ok_and(|h| (h - height).abs() <= CONFIG.lake_tolerance)
+		});
+
+		if flat {
+		    *h = height;
+		}
+	    }
+
+	    res.push((row, v, land));
+	}
+
+	Ok(res)
+    }
+
+    /*
+    Surface height of a lake at a coordinate. The lakes of the cell of the
+    coordinate and the cells around it are candidates, since the shore may
+    be anywhere within a cell. Of those, we take the one with the surface
+    closest to the height of the terrain. None if there are no lakes around
+    the coordinate.
+     */
+    pub fn level(&self, c: &Coord, height: f32) -> Option<f32> {
+	let g = &self.grid;
+	let col = ((c.e - g.e0)/g.cell).floor();
+	let row = ((c.n - g.n0)/g.cell).floor();
+
+	if col < -1.0 || row < -1.0 || col > g.cols as f32 || row > g.rows as f32 {
+	    return None;
+	}
+
+	let mut res: Option<f32> = None;
+	for (dc, dr) in NEIGHBOURS.iter().chain(&[(0, 0)]) {
+	    let label = match g.index(col as isize + dc, row as isize + dr) {
+		Some(i) => self.labels[i],
+		None => continue,
+	    };
+
+	    if label == 0 {
+		continue;
+	    }
+
+	    let level = self.levels[(label - 1) as usize];
+	    if res.is_none_or(|r| (level - height).abs() < (r - height).abs()) {
+		res = Some(level);
+	    }
+	}
+
+	res
+    }
+
+    /*
+    Distance (meters) from a coordinate to the shore, interpolated between
+    the middles of the cells. None if it is further than shore_distance,
+    or outside the grid.
+     */
+    pub fn shore_distance(&self, c: &Coord) -> Option<f32> {
+	if self.shore.is_empty() {
+	    return None;
+	}
+
+	let g = &self.grid;
+	let x = (c.e - g.e0)/g.cell - 0.5;
+	let y = (c.n - g.n0)/g.cell - 0.5;
+	if x < -0.5 || y < -0.5 ||
+	    x > g.cols as f32 - 0.5 || y > g.rows as f32 - 0.5 {
+	    return None;
+	}
+
+	// Cells around the coordinate, clamped at the edges of the grid
+	let col = (x.floor() as isize).clamp(0, g.cols as isize - 1);
+	let row = (y.floor() as isize).clamp(0, g.rows as isize - 1);
+	let col1 = (col + 1).min(g.cols as isize - 1);
+	let row1 = (row + 1).min(g.rows as isize - 1);
+	let u = (x - col as f32).clamp(0.0, 1.0);
+	let v = (y - row as f32).clamp(0.0, 1.0);
+	let d = |col, row| self.shore[g.index(col, row).unwrap()];
+
+	let dist = (d(col, row)*(1.0 - u) + d(col1, row)*u)*(1.0 - v) +
+	    (d(col, row1)*(1.0 - u) + d(col1, row1)*u)*v;
+
+	// Far from land, the distance may be NaN
+	if dist <= CONFIG.shore_distance { Some(dist) } else { None }
+    }
+}
+
+/*
+Distance (meters) from each cell to the nearest cell on land, by a chamfer
+distance transform: one pass forwards and one backwards over the grid, each
+taking the distance through the neighbours already passed. The distances
+are exact along the rows, columns and diagonals, and up to 8% too long in
+other directions. Without land, the distances are f32::MAX.
+ */
+fn shore_distances(grid: &Grid, land: &[bool]) -> Vec<f32> {
+    let straight = grid.cell;
+    let diagonal = grid.cell*2.0_f32.sqrt();
+    let forward = [(-1, 0, straight), (-1, -1, diagonal), (0, -1, straight),
+		   (1, -1, diagonal)];
+    let backward = [(1, 0, straight), (1, 1, diagonal), (0, 1, straight),
+		    (-1, 1, diagonal)];
+    let mut dist: Vec<f32> = land.iter()
+	.map(|l| if *l { 0.0 } else { f32::MAX })
+	.collect();
+
+    let mut pass = |i: usize, neighbours: &[(isize, isize, f32)]| {
+	let (col, row) = ((i % grid.cols) as isize, (i/grid.cols) as isize);
+	for (dc, dr, d) in neighbours {
+	    if let Some(j) = grid.index(col + dc, row + dr) {
+		dist[i] = dist[i].min(dist[j] + d);
+	    }
+	}
+    };
+
+    for i in 0..land.len() {
+	pass(i, &forward);
+    }
+    for i in (0..land.len()).rev() {
+	pass(i, &backward);
+    }
+
+    dist
+}
+
+/*
+Label the connected regions of cells with a height (not NaN), by flood fill.
+Neighbour cells are connected if their height is within the tolerance of
+the first cell of the region, or always without a tolerance. Returns the
+label of each cell, and the surface height of each region.
+ */
+fn label(grid: &Grid, heights: &[f32], tolerance: Option<f32>, water_level: f32)
+	 -> (Vec<u32>, Vec<f32>) {
+    let mut labels = vec![0u32; heights.len()];
+    let mut levels = Vec::new();
+    let mut stack = Vec::new();
+
+    for start in 0..heights.len() {
+	if labels[start] != 0 || heights[start].is_nan() {
+	    continue;
+	}
+
+	let seed = heights[start];
+	let l = levels.len() as u32 + 1;
+	let mut region = Vec::new();
+
+	labels[start] = l;
+	stack.push(start);
+
+	while let Some(i) = stack.pop() {
+	    region.push(heights[i]);
+	    let (col, row) = ((i % grid.cols) as isize, (i/grid.cols) as isize);
+
+	    for (dc, dr) in NEIGHBOURS {
+		let j = match grid.index(col + dc, row + dr) {
+		    Some(j) => j,
+		    None => continue,
+		};
+
+		if labels[j] == 0 && !heights[j].is_nan() &&
+		    tolerance.is_none_or(|t| (heights[j] - seed).abs() <= t) {
+		    labels[j] = l;
+		    stack.push(j);
+		}
+	    }
+	}
+
+	levels.push(surface(&mut region, water_level));
+    }
+
+    (labels, levels)
+}
+
+// Surface height of a lake from the heights of its cells: the median
+// height, or water_level if the lake reaches the sea
+fn surface(heights: &mut [f32], water_level: f32) -> f32 {
+    let min = heights.iter().cloned().fold(f32::MAX, f32::min);
+    if min <= water_level {
+	return water_level;
+    }
+
+    let mid = heights.len()/2;
+    *heights.select_nth_unstable_by(mid, f32::total_cmp).1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N: f32 = f32::NAN;
+
+    fn grid(cols: usize, rows: usize) -> Grid {
+	Grid { e0: 0.0, n0: 0.0, cell: 10.0, cols, rows }
+    }
+
+    #[test]
+    fn separate_lakes_have_own_levels() {
+	let heights = [100.0, 100.0, N, 200.0,
+		       100.0, 100.0, N, 200.0];
+	let (labels, levels) = label(&grid(4, 2), &heights, Some(0.1), 0.0);
+
+	assert_eq!(labels, [1, 1, 0, 2, 1, 1, 0, 2]);
+	assert_eq!(levels, [100.0, 200.0]);
+    }
+
+    #[test]
+    fn slope_does_not_join_lakes() {
+	// Each step is within the tolerance, but not the whole slope
+	let heights = [10.0, 10.04, 10.08, 10.12, 10.16];
+	let (labels, levels) = label(&grid(5, 1), &heights, Some(0.1), 0.0);
+
+	assert_eq!(labels, [1, 1, 1, 2, 2]);
+	assert_eq!(levels.len(), 2);
+    }
+
+    #[test]
+    fn mask_region_has_median_level() {
+	// Without a tolerance, all neighbours are joined. The shore cell does
+	// not move the median.
+	let heights = [50.0, 50.0, 53.0, 50.2, 49.9];
+	let (labels, levels) = label(&grid(5, 1), &heights, None, 0.0);
+
+	assert_eq!(labels, [1; 5]);
+	assert_eq!(levels, [50.0]);
+    }
+
+    #[test]
+    fn shore_distance_along_rows_and_diagonals() {
+	// Land in the corner of the grid
+	let g = grid(4, 3);
+	let mut land = [false; 12];
+	land[0] = true;
+	let dist = shore_distances(&g, &land);
+
+	assert_eq!(dist[0], 0.0);
+	assert_eq!(dist[3], 30.0);
+	assert_eq!(dist[8], 20.0);
+	assert!((dist[10] - 20.0*2.0_f32.sqrt()).abs() < 1e-3);
+
+	// No land
+	assert!(shore_distances(&g, &[false; 12]).iter().all(|d| *d == f32::MAX));
+    }
+
+    #[test]
+    fn lake_reaching_sea_is_sea() {
+	let heights = [0.0, 3.0, 5.0];
+	let (_, levels) = label(&grid(3, 1), &heights, None, 0.0);
+
+	assert_eq!(levels, [0.0]);
+    }
+}
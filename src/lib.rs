@@ -3,6 +3,25 @@ mod renderer;
 mod progress;
 mod canvas;
 mod color;
+mod maps;
+mod detail;
+mod pyramid;
+mod lakes;
+mod atmosphere;
+mod sky;
+mod moon;
+mod sampling;
+mod projection;
+mod stereo;
+mod view;
+mod animation;
+mod gbuffer;
+mod raster;
+mod water;
+mod random;
 
 pub use crate::renderer::Renderer;
 pub use crate::config::CONFIG;
+pub use crate::detail::Detail;
+pub use crate::pyramid::Pyramid;
+pub use crate::view::View;
@@ -0,0 +1,22 @@
+// Terrain heights from the maps
+use hoydedata::{Atlas, Coord, Result};
+
+/*
+Terrain height and gradient at a coordinate, as looked up in the maps. The
+renderer looks up the 10m maps through this trait, so that it can also
+render other terrain, e.g. in the tests.
+ */
+pub trait Heights {
+    fn lookup(&self, c: &Coord) -> Result<f32>;
+    fn lookup_with_gradient(&self, c: &Coord) -> Result<(f32, f32, f32)>;
+}
+
+impl Heights for Atlas {
+    fn lookup(&self, c: &Coord) -> Result<f32> {
+	Atlas::lookup(self, c)
+    }
+
+    fn lookup_with_gradient(&self, c: &Coord) -> Result<(f32, f32, f32)> {
+	Atlas::lookup_with_gradient(self, c)
+    }
+}
@@ -0,0 +1,110 @@
+// Position and phase of the moon. The formulas are the same low precision
+// formulas as used by the sun crate (from suncalc), based on "Astronomy
+// Answers" by Aphayes.
+
+use std::f64::consts::PI;
+
+const RAD: f64 = PI/180.0;
+const DAY_MS: f64 = 86400000.0;
+const J1970: f64 = 2440588.0;
+const J2000: f64 = 2451545.0;
+
+// Obliquity of the earth
+const OBLIQUITY: f64 = RAD*23.4397;
+
+// Distance to the sun (km)
+const SUN_DIST: f64 = 149598000.0;
+
+fn to_days(unixtime_ms: i64) -> f64 {
+    (unixtime_ms as f64)/DAY_MS - 0.5 + J1970 - J2000
+}
+
+fn right_ascension(l: f64, b: f64) -> f64 {
+    (l.sin()*OBLIQUITY.cos() - b.tan()*OBLIQUITY.sin()).atan2(l.cos())
+}
+
+fn declination(l: f64, b: f64) -> f64 {
+    (b.sin()*OBLIQUITY.cos() + b.cos()*OBLIQUITY.sin()*l.sin()).asin()
+}
+
+fn sidereal_time(d: f64, lw: f64) -> f64 {
+    RAD*(280.16 + 360.9856235*d) - lw
+}
+
+// Ecliptic coordinates of the sun: (right ascension, declination)
+fn sun_coords(d: f64) -> (f64, f64) {
+    let m = RAD*(357.5291 + 0.98560028*d);
+    let c = RAD*(1.9148*m.sin() + 0.02*(2.0*m).sin() + 0.0003*(3.0*m).sin());
+    let l = m + c + RAD*102.9372 + PI;
+
+    (right_ascension(l, 0.0), declination(l, 0.0))
+}
+
+// Geocentric coordinates of the moon: (right ascension, declination,
+// distance in km)
+fn moon_coords(d: f64) -> (f64, f64, f64) {
+    let l = RAD*(218.316 + 13.176396*d);
+    let m = RAD*(134.963 + 13.064993*d);
+    let f = RAD*(93.272 + 13.229350*d);
+
+    let lon = l + RAD*6.289*m.sin();
+    let lat = RAD*5.128*f.sin();
+    let dist = 385001.0 - 20905.0*m.cos();
+
+    (right_ascension(lon, lat), declination(lon, lat), dist)
+}
+
+// Azimuth (from north, clockwise, as the sun crate) and altitude of the
+// moon, in radians.
+pub fn pos(unixtime_ms: i64, lat: f64, lon: f64) -> (f64, f64) {
+    let lw = -RAD*lon;
+    let phi = RAD*lat;
+    let d = to_days(unixtime_ms);
+
+    let (ra, dec, _) = moon_coords(d);
+    let h = sidereal_time(d, lw) - ra;
+
+    let az = h.sin().atan2(h.cos()*phi.sin() - dec.tan()*phi.cos()) + PI;
+    let mut alt = (phi.sin()*dec.sin() + phi.cos()*dec.cos()*h.cos()).asin();
+
+    // Atmospheric refraction
+    if alt > 0.0 {
+	alt += 0.0002967/(alt + 0.00312536/(alt + 0.08901179)).tan();
+    }
+
+    (az, alt)
+}
+
+// Illuminated fraction of the moon disc, 0 = new moon, 1 = full moon
+pub fn illumination(unixtime_ms: i64) -> f64 {
+    let d = to_days(unixtime_ms);
+    let (s_ra, s_dec) = sun_coords(d);
+    let (m_ra, m_dec, m_dist) = moon_coords(d);
+
+    let phi = (s_dec.sin()*m_dec.sin() +
+	       s_dec.cos()*m_dec.cos()*(s_ra - m_ra).cos()).acos();
+    let inc = (SUN_DIST*phi.sin()).atan2(m_dist - SUN_DIST*phi.cos());
+
+    0.5*(1.0 + inc.cos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_as_suncalc() {
+	// Test case of suncalc, 2013-03-05 UTC at 50.5 N 30.5 E. Suncalc has
+	// the azimuth from south.
+	let (az, alt) = pos(1362441600000, 50.5, 30.5);
+	assert!((az - (PI - 0.9783999522438226)).abs() < 1e-9);
+	assert!((alt - 0.014551482243892251).abs() < 1e-9);
+    }
+
+    #[test]
+    fn illumination_at_full_and_new_moon() {
+	// Full moon 2024-01-25 17:54 UTC, new moon 2024-01-11 11:57 UTC
+	assert!(illumination(1706205240000) > 0.99);
+	assert!(illumination(1704974220000) < 0.01);
+    }
+}
@@ -7,7 +7,7 @@ pub struct Progress {
 impl Progress {
     pub fn new() -> Self {
 	let p = ProgressBar::new(1);
-	p.set_style(ProgressStyle::with_template("[{elapsed_precise}] [{bar:40}] {pos:>7}/{len:7} lines")
+	p.set_style(ProgressStyle::with_template("[{elapsed_precise}] [{bar:40}] {pos:>7}/{len:7} columns")
 		    .unwrap()
 		    .progress_chars("=> "));
 	
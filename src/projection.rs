@@ -0,0 +1,475 @@
+// Mapping from image positions to view angles
+use crate::config::CONFIG;
+
+use hoydedata::{Error, Result};
+use std::f32::consts::PI;
+
+// Projections from the view to the image
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    // Flat image plane, like a normal camera
+    Perspective,
+    // Pinhole camera with a flat sensor, given by focal length and sensor
+    // size, and optional lens shift
+    Rectilinear,
+    // Horizontal position linear in azimuth, and vertical position linear
+    // in elevation, like a panorama
+    Cylindrical,
+    // Fisheye, with the distance from the middle of the image linear in the
+    // angle from the view direction
+    Equidistant,
+    // Fisheye, which keeps the shape of small objects, but stretches the
+    // edges of the image
+    Stereographic,
+    // Stereographic fisheye pointing straight down. The horizon is a circle
+    // around the ground below the observer.
+    LittlePlanet,
+    // Full sphere, with horizontal position linear in azimuth over the full
+    // circle, and vertical position linear in elevation from zenith to
+    // nadir. The image should be twice as wide as high.
+    Equirectangular,
+    // Full sphere as six perspective images with 90 degrees field of view,
+    // the faces of a cube. The faces are put side by side in the image.
+    Cubemap,
+}
+
+impl Kind {
+    pub fn from(name: &str) -> Result<Kind> {
+	match name {
+	    "perspective" => Ok(Kind::Perspective),
+	    "rectilinear" => Ok(Kind::Rectilinear),
+	    "cylindrical" => Ok(Kind::Cylindrical),
+	    "equidistant" => Ok(Kind::Equidistant),
+	    "stereographic" => Ok(Kind::Stereographic),
+	    "little_planet" => Ok(Kind::LittlePlanet),
+	    "equirectangular" => Ok(Kind::Equirectangular),
+	    "cubemap" => Ok(Kind::Cubemap),
+	    _ => Err(Error::Generic(format!("Unknown projection {}", name))),
+	}
+    }
+
+    pub fn from_config() -> Result<Kind> {
+	Kind::from(&CONFIG.projection)
+    }
+}
+
+// Names of the cube faces, in the order they are put in the image
+pub const CUBE_FACES: [&str; 6] = ["right", "left", "up", "down", "front", "back"];
+
+// Check if the image covers a full circle around the observer. Full circle
+// images are centered on south, with the seam at north, and do not use the
+// target.
+pub fn full_circle(kind: Kind, width_angle: f32) -> bool {
+    match kind {
+	Kind::Perspective | Kind::Rectilinear => false,
+	Kind::Equidistant | Kind::Stereographic | Kind::LittlePlanet => false,
+	Kind::Cylindrical => width_angle >= PI,
+	Kind::Equirectangular | Kind::Cubemap => true,
+    }
+}
+
+// Horizontal angle of view (radians) from the middle to the edge of the
+// image, on the side furthest from the middle, with the camera pitched
+// (radians) up or down. When the camera is rolled, the corners may be
+// further out to the side, and we use the angle to the corners.
+pub fn half_width_angle(kind: Kind, width_angle: f32, pitch: f32) -> f32 {
+    if full_circle(kind, width_angle) {
+	return PI;
+    }
+
+    let w = CONFIG.width as f32;
+    let h = CONFIG.height as f32;
+    let diagonal = (w*w + h*h).sqrt()/w;
+
+    let mut a = match kind {
+	Kind::Rectilinear => {
+	    ((0.5*CONFIG.sensor_width + CONFIG.lens_shift_x.abs())/
+	     CONFIG.focal_length).atan()
+	},
+	kind @ (Kind::Equidistant | Kind::Stereographic | Kind::LittlePlanet) => {
+	    // The fisheye sees all directions within the angle to the
+	    // corners (or the mask) from the view direction. When this
+	    // includes zenith or nadir, it sees all around.
+	    let mut t = match kind {
+		Kind::Equidistant => width_angle*diagonal,
+		_ => 2.0*(diagonal*(0.5*width_angle).tan()).atan(),
+	    };
+	    if CONFIG.mask_angle > 0.0 {
+		t = t.min(CONFIG.mask_angle.to_radians());
+	    }
+
+	    let pitch = match kind {
+		Kind::LittlePlanet => -0.5*PI,
+		_ => pitch,
+	    };
+
+	    if t + pitch.abs() >= 0.5*PI {
+		return PI;
+	    }
+
+	    return (t.sin()/pitch.cos()).asin();
+	},
+	_ => width_angle,
+    };
+
+    if CONFIG.roll.unwrap_or(0.0) != 0.0 {
+	a *= diagonal;
+    }
+
+    a
+}
+
+// Number of pixels per radian in the middle of the image
+pub fn focus_depth(kind: Kind, width_angle: f32) -> f32 {
+    let width = CONFIG.width as f32;
+
+    // Size of a pixel on the sensor (mm)
+    let pixel = CONFIG.sensor_width/width;
+
+    match kind {
+	Kind::Perspective => width/(2.0*width_angle.tan()),
+	Kind::Rectilinear => CONFIG.focal_length/pixel,
+	Kind::Cylindrical => width/(2.0*width_angle),
+	Kind::Equidistant => width/(2.0*width_angle),
+	Kind::Stereographic | Kind::LittlePlanet => {
+	    width/(4.0*(0.5*width_angle).tan())
+	},
+	Kind::Equirectangular => width/(2.0*PI),
+	Kind::Cubemap => (CONFIG.height as f32)/2.0,
+    }
+}
+
+/*
+Projection between image positions and view angles (directional angle and
+vertical angle). Image positions may be fractional, for sub-pixel samples.
+ */
+pub struct Projection {
+    kind: Kind,
+    width: f32,
+    height: f32,
+    // Number of pixels per radian in the middle of the image
+    focus_depth: f32,
+    // Lens shift of the rectilinear camera (pixels)
+    shift_x: f32,
+    shift_y: f32,
+    h_middle: f32,
+    v_middle: f32,
+    // Rotation of the camera around the view direction, clockwise seen
+    // from behind the camera (radians)
+    roll: f32,
+}
+
+impl Projection {
+    // Projection of a kind, with the given view angles in the middle of
+    // the image, roll and half horizontal view angle. The full sphere
+    // projections ignore the roll and the view angle.
+    pub fn new(kind: Kind, h_middle: f32, v_middle: f32, roll: f32,
+	       width_angle: f32) -> Self {
+	let width = CONFIG.width as f32;
+	let height = CONFIG.height as f32;
+
+	// Size of a pixel on the sensor (mm)
+	let pixel = CONFIG.sensor_width/width;
+
+	// The little planet always points straight down
+	let mut v_middle = v_middle;
+	if kind == Kind::LittlePlanet {
+	    v_middle = -0.5*PI;
+	}
+
+	Self {
+	    kind,
+	    width,
+	    height,
+	    focus_depth: focus_depth(kind, width_angle),
+	    shift_x: CONFIG.lens_shift_x/pixel,
+	    shift_y: CONFIG.lens_shift_y/pixel,
+	    h_middle,
+	    v_middle,
+	    roll,
+	}
+    }
+
+    pub fn kind(&self) -> Kind {
+	self.kind
+    }
+
+    pub fn focus_depth(&self) -> f32 {
+	self.focus_depth
+    }
+
+    pub fn h_middle(&self) -> f32 {
+	self.h_middle
+    }
+
+    // View angles in the middle of the image
+    pub fn middle(&self) -> (f32, f32) {
+	(self.h_middle, self.v_middle)
+    }
+
+    pub fn roll(&self) -> f32 {
+	self.roll
+    }
+
+    // Rotate an offset in the image (right and up) by the roll, giving the
+    // offset in the image of a level camera.
+    fn unroll(&self, dx: f32, dy: f32) -> (f32, f32) {
+	if self.roll == 0.0 {
+	    return (dx, dy);
+	}
+
+	let (s, c) = self.roll.sin_cos();
+	(dx*c + dy*s, dy*c - dx*s)
+    }
+
+    // View angles of an image position. Image positions have whole numbers
+    // at the pixel centres, like the sample offsets, so the image goes from
+    // -0.5 to width - 0.5. All projections work on the position from the
+    // upper left corner of the image, x + 0.5 and y + 0.5.
+    pub fn angles(&self, x: f32, y: f32) -> (f32, f32) {
+	let d = self.focus_depth;
+	let (x, y) = (x + 0.5, y + 0.5);
+
+	match self.kind {
+	    Kind::Perspective => {
+		let (dx, dy) = self.unroll(x - self.width/2.0, self.height/2.0 - y);
+		(self.h_middle - (dx/d).atan(), self.v_middle + (dy/d).atan())
+	    },
+	    Kind::Rectilinear => self.camera_angles(x, y),
+	    Kind::Equidistant | Kind::Stereographic | Kind::LittlePlanet => {
+		let (dx, dy) = self.unroll(x - self.width/2.0, self.height/2.0 - y);
+		let t = self.fisheye_angle(dx, dy);
+		let phi = dy.atan2(dx);
+
+		// Direction in the camera frame: forward, right and up
+		let (st, ct) = t.sin_cos();
+		self.camera_direction(ct, st*phi.cos(), st*phi.sin())
+	    },
+	    Kind::Cylindrical => {
+		let (dx, dy) = self.unroll(x - self.width/2.0, self.height/2.0 - y);
+		(self.h_middle - dx/d, self.v_middle + dy/d)
+	    },
+	    Kind::Equirectangular => {
+		(self.h_middle + (self.width/2.0 - x)/d,
+		 (0.5 - y/self.height)*PI)
+	    },
+	    Kind::Cubemap => self.cube_angles(x, y),
+	}
+    }
+
+    /*
+    View angles of a position on the sensor of a pinhole camera. The camera
+    points towards the middle angles, and is rotated by the roll around the
+    optical axis. The ray through a pixel center goes from the pixel on the
+    sensor through the pinhole at focal length from the sensor. Lens shift
+    moves the image on the sensor, right and up.
+     */
+    fn camera_angles(&self, x: f32, y: f32) -> (f32, f32) {
+	// Position on the sensor relative to the optical axis (pixels)
+	let (sx, sy) = self.unroll(x - self.width/2.0 + self.shift_x,
+				   self.height/2.0 - y + self.shift_y);
+
+	self.camera_direction(self.focus_depth, sx, sy)
+    }
+
+    // View angles of a direction given in the frame of a level camera
+    // pointing towards the middle angles, as components forward, right
+    // and up.
+    fn camera_direction(&self, f: f32, r: f32, u: f32) -> (f32, f32) {
+	// Forward, right and up vectors of the camera
+	let (ch, sh) = (self.h_middle.cos(), self.h_middle.sin());
+	let (cv, sv) = (self.v_middle.cos(), self.v_middle.sin());
+
+	let de = f*cv*ch + r*sh - u*sv*ch;
+	let dn = f*cv*sh - r*ch - u*sv*sh;
+	let dz = f*sv + u*cv;
+
+	(dn.atan2(de), dz.atan2((de*de + dn*dn).sqrt()))
+    }
+
+    // Angle from the view direction of a fisheye image position, offset
+    // from the middle of the image
+    fn fisheye_angle(&self, dx: f32, dy: f32) -> f32 {
+	let r = (dx*dx + dy*dy).sqrt();
+
+	match self.kind {
+	    Kind::Equidistant => r/self.focus_depth,
+	    _ => 2.0*(0.5*r/self.focus_depth).atan(),
+	}
+    }
+
+    // Check if an image position is outside the circular mask of a
+    // fisheye, or outside the full sphere. Masked pixels are black.
+    pub fn masked(&self, x: f32, y: f32) -> bool {
+	match self.kind {
+	    Kind::Equidistant | Kind::Stereographic | Kind::LittlePlanet => {
+		let t = self.fisheye_angle(x + 0.5 - self.width/2.0,
+					   self.height/2.0 - y - 0.5);
+		t > PI || (CONFIG.mask_angle > 0.0 &&
+			   t > CONFIG.mask_angle.to_radians())
+	    },
+	    _ => false,
+	}
+    }
+
+    /*
+    View angles of a position in the cube faces. Each face is height pixels
+    wide. The side faces are seen with up upwards. The up face is seen
+    with the back face upwards, and the down face with the front face
+    upwards, as when tilting the head from the front face.
+     */
+    fn cube_angles(&self, x: f32, y: f32) -> (f32, f32) {
+	let s = self.height;
+	let face = ((x/s).floor() as usize).min(5);
+
+	// Position in face from -1 to 1, right and down
+	let u = 2.0*(x - (face as f32)*s)/s - 1.0;
+	let v = 2.0*y/s - 1.0;
+
+	// Horizontal unit vectors (east, north) towards front and right
+	let (fe, fn_) = (self.h_middle.cos(), self.h_middle.sin());
+	let (re, rn) = (fn_, -fe);
+
+	match face {
+	    // Side faces: right, left, front and back
+	    0 | 1 | 4 | 5 => {
+		let a = match face {
+		    0 => self.h_middle - 0.5*PI,
+		    1 => self.h_middle + 0.5*PI,
+		    4 => self.h_middle,
+		    _ => self.h_middle + PI,
+		};
+
+		(a - u.atan(), (-v/(1.0 + u*u).sqrt()).atan())
+	    },
+	    // Up and down faces
+	    _ => {
+		let (sv, sz) = if face == 2 { (1.0, 1.0) } else { (-1.0, -1.0) };
+		let de = u*re + sv*v*fe;
+		let dn = u*rn + sv*v*fn_;
+
+		(dn.atan2(de), sz*(1.0/(de*de + dn*dn).sqrt()).atan())
+	    },
+	}
+    }
+
+    // Check if all positions in column x have the same directional angle,
+    // with the vertical angle decreasing downwards. Such columns can be
+    // traced bottom-up, starting each ray at the hit of the ray below.
+    pub fn coherent(&self, x: f32) -> bool {
+	match self.kind {
+	    // Columns are not vertical when the camera is rolled
+	    Kind::Perspective | Kind::Cylindrical => self.roll == 0.0,
+	    Kind::Equidistant | Kind::Stereographic | Kind::LittlePlanet => false,
+	    // Vertical lines converge when the camera is tilted
+	    Kind::Rectilinear => self.v_middle == 0.0 && self.roll == 0.0,
+	    Kind::Cubemap => {
+		let face = (((x + 0.5)/self.height).floor() as usize).min(5);
+		face != 2 && face != 3
+	    },
+	    _ => true,
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn projection(kind: Kind, focus_depth: f32, v_middle: f32) -> Projection {
+	Projection {
+	    kind,
+	    width: 400.0,
+	    height: 200.0,
+	    focus_depth,
+	    shift_x: 0.0,
+	    shift_y: 0.0,
+	    h_middle: 1.0,
+	    v_middle,
+	    roll: 0.0,
+	}
+    }
+
+    // Image position of a direction seen by a pinhole camera without lens
+    // shift and roll
+    fn pinhole_position(p: &Projection, h: f32, v: f32) -> (f32, f32) {
+	let dir = [v.cos()*h.cos(), v.cos()*h.sin(), v.sin()];
+	let (ch, sh) = (p.h_middle.cos(), p.h_middle.sin());
+	let (cv, sv) = (p.v_middle.cos(), p.v_middle.sin());
+
+	let f = dir[0]*cv*ch + dir[1]*cv*sh + dir[2]*sv;
+	let r = dir[0]*sh - dir[1]*ch;
+	let u = -dir[0]*sv*ch - dir[1]*sv*sh + dir[2]*cv;
+
+	(0.5*p.width + p.focus_depth*r/f - 0.5,
+	 0.5*p.height - p.focus_depth*u/f - 0.5)
+    }
+
+    fn assert_round_trip(p: &Projection,
+			 position: impl Fn(f32, f32) -> (f32, f32)) {
+	for (x, y) in [(0.0, 0.0), (199.5, 99.5), (37.0, 150.0), (399.0, 199.0)] {
+	    let (h, v) = p.angles(x, y);
+	    let (x1, y1) = position(h, v);
+	    assert!((x1 - x).abs() < 1e-2 && (y1 - y).abs() < 1e-2,
+		    "({}, {}) gave ({}, {})", x, y, x1, y1);
+	}
+    }
+
+    #[test]
+    fn perspective_round_trip() {
+	let p = projection(Kind::Perspective, 300.0, 0.1);
+	assert_round_trip(&p, |h, v| {
+	    (0.5*p.width + p.focus_depth*(p.h_middle - h).tan() - 0.5,
+	     0.5*p.height - p.focus_depth*(v - p.v_middle).tan() - 0.5)
+	});
+    }
+
+    #[test]
+    fn rectilinear_round_trip() {
+	let p = projection(Kind::Rectilinear, 300.0, 0.2);
+	assert_round_trip(&p, |h, v| pinhole_position(&p, h, v));
+    }
+
+    #[test]
+    fn cylindrical_round_trip() {
+	let p = projection(Kind::Cylindrical, 100.0, 0.1);
+	assert_round_trip(&p, |h, v| {
+	    (0.5*p.width + p.focus_depth*(p.h_middle - h) - 0.5,
+	     0.5*p.height - p.focus_depth*(v - p.v_middle) - 0.5)
+	});
+    }
+
+    #[test]
+    fn equirectangular_round_trip() {
+	let p = projection(Kind::Equirectangular, 400.0/(2.0*PI), 0.0);
+	assert_round_trip(&p, |h, v| {
+	    (0.5*p.width - p.focus_depth*(h - p.h_middle) - 0.5,
+	     (0.5 - v/PI)*p.height - 0.5)
+	});
+    }
+
+    #[test]
+    fn pixel_centres_are_symmetric() {
+	// The middle of the image is between the two middle pixels
+	let p = projection(Kind::Perspective, 300.0, 0.0);
+	let (h, v) = p.angles(199.5, 99.5);
+	assert!((h - p.h_middle).abs() < 1e-6 && v.abs() < 1e-6);
+
+	// The first and last pixels of a full circle are half a pixel from
+	// the seam behind the middle
+	let p = projection(Kind::Equirectangular, 400.0/(2.0*PI), 0.0);
+	let (h0, _) = p.angles(0.0, 0.0);
+	let (h1, _) = p.angles(399.0, 0.0);
+	let half_pixel = PI/400.0;
+	assert!((h0 - p.h_middle - (PI - half_pixel)).abs() < 1e-5);
+	assert!((h1 - p.h_middle + (PI - half_pixel)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn cube_front_face_middle() {
+	let mut p = projection(Kind::Cubemap, 100.0, 0.0);
+	p.width = 1200.0;
+	let (h, v) = p.angles(4.5*200.0 - 0.5, 99.5);
+	assert!((h - p.h_middle).abs() < 1e-6 && v.abs() < 1e-6);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::maps::Heights;
+
+use hoydedata::{Atlas, Coord, Error, Result};
+use std::thread::spawn;
+
+// Size of the cells on the lowest level of the pyramid (meters)
+const BASE_CELL: f32 = 80.0;
+
+// Number of levels. The cells on the top level are 80*2^6 = 5120m wide.
+const LEVELS: usize = 7;
+
+// Spacing of the samples in the 10m atlas when calculating the base level
+const SAMPLE_DIST: f32 = 10.0;
+
+// One level of the pyramid. Each cell holds the maximum terrain height
+// within the cell.
+struct Level {
+    cell: f32,
+    cols: usize,
+    rows: usize,
+    max: Vec<f32>,
+}
+
+impl Level {
+    fn get(&self, col: usize, row: usize) -> f32 {
+	self.max[row*self.cols + col]
+    }
+
+    // The level above, where each cell is the maximum of four cells on
+    // this level
+    fn reduce(&self) -> Level {
+	let cols = self.cols.div_ceil(2);
+	let rows = self.rows.div_ceil(2);
+	let mut max = vec![0.0; cols*rows];
+
+	for row in 0..rows {
+	    for col in 0..cols {
+		let mut m = f32::MIN;
+		for (c, r) in [(2*col, 2*row), (2*col + 1, 2*row),
+			       (2*col, 2*row + 1), (2*col + 1, 2*row + 1)] {
+		    if c < self.cols && r < self.rows {
+			m = m.max(self.get(c, r));
+		    }
+		}
+		max[row*cols + col] = m;
+	    }
+	}
+
+	Level {
+	    cell: self.cell*2.0,
+	    cols,
+	    rows,
+	    max,
+	}
+    }
+}
+
+/*
+Hierarchical max-height pyramid over the 10m atlas. The pyramid covers a
+rectangle around the rendered view. A ray which is above the maximum
+height of a cell all the way through the cell can skip the whole cell.
+ */
+pub struct Pyramid {
+    e0: f32,
+    n0: f32,
+    levels: Vec<Level>,
+}
+
+impl Pyramid {
+    // Build pyramid covering the rectangle from c_min to c_max. The base
+    // level is calculated by a number of threads, each with its own atlas.
+    pub fn build(c_min: Coord, c_max: Coord, threads: usize) -> Result<Self> {
+	let (origin, cols, rows) = Pyramid::grid(c_min, c_max);
+
+	let mut workers = Vec::new();
+	for t in 0..threads {
+	    workers.push(spawn(move || {
+		Atlas::new(10.0, None)
+		    .map(|atlas10| {
+			Pyramid::build_rows(&atlas10, origin, cols, rows, t, threads)
+		    })
+		    .map_err(|e| e.to_string())
+	    }));
+	}
+
+	let mut base = Vec::new();
+	for w in workers {
+	    match w.join().unwrap() {
+		Ok(res) => base.extend(res),
+		Err(e) => {
+		    return Err(Error::Generic(e));
+		}
+	    }
+	}
+
+	Ok(Pyramid::from_rows(origin, cols, rows, base))
+    }
+
+    // Pyramid over other terrain than the 10m maps, for the tests
+    #[cfg(test)]
+    pub fn with_heights(maps: &dyn Heights, c_min: Coord, c_max: Coord) -> Self {
+	let (origin, cols, rows) = Pyramid::grid(c_min, c_max);
+	let base = Pyramid::build_rows(maps, origin, cols, rows, 0, 1);
+
+	Pyramid::from_rows(origin, cols, rows, base)
+    }
+
+    // Corner and size of the base level covering the rectangle from c_min
+    // to c_max
+    fn grid(c_min: Coord, c_max: Coord) -> (Coord, usize, usize) {
+	let mut origin = c_min;
+	origin.e = (c_min.e/BASE_CELL).floor()*BASE_CELL;
+	origin.n = (c_min.n/BASE_CELL).floor()*BASE_CELL;
+	let cols = ((c_max.e - origin.e)/BASE_CELL).ceil().max(1.0) as usize;
+	let rows = ((c_max.n - origin.n)/BASE_CELL).ceil().max(1.0) as usize;
+
+	(origin, cols, rows)
+    }
+
+    // Pyramid from the rows of the base level
+    fn from_rows(origin: Coord, cols: usize, rows: usize,
+		 base: Vec<(usize, Vec<f32>)>) -> Self {
+	let mut max = vec![0.0; cols*rows];
+	for (row, v) in base {
+	    max[row*cols..(row + 1)*cols].copy_from_slice(&v);
+	}
+
+	let mut levels = vec![Level {
+	    cell: BASE_CELL,
+	    cols,
+	    rows,
+	    max,
+	}];
+
+	// Each cell on the higher levels is the maximum of four cells on the
+	// level below.
+	for l in 1..LEVELS {
+	    let above = levels[l - 1].reduce();
+	    levels.push(above);
+	}
+
+	Self {
+	    e0: origin.e,
+	    n0: origin.n,
+	    levels,
+	}
+    }
+
+    // Calculate every n'th row of the base level, starting at row t.
+    fn build_rows(maps: &dyn Heights, origin: Coord, cols: usize, rows: usize,
+		  t: usize, n: usize) -> Vec<(usize, Vec<f32>)> {
+	let mut c = origin;
+	let mut res = Vec::new();
+	let samples = (BASE_CELL/SAMPLE_DIST) as usize;
+
+	for row in (t..rows).step_by(n) {
+	    let mut v = vec![0.0; cols];
+
+	    for (col, max) in v.iter_mut().enumerate() {
+		// Sample the cell, including the border one sample outside
+		// the cell. The samples may not be on the grid of the map,
+		// so we add the gradient times half the sample distance to
+		// get an upper bound of the height between the samples.
+		// Outside the maps, we assume sea level.
+		let mut m = 0.0_f32;
+		for i in 0..(samples + 3) {
+		    for j in 0..(samples + 3) {
+			c.e = origin.e + (col as f32)*BASE_CELL + ((i as f32) - 1.0)*SAMPLE_DIST;
+			c.n = origin.n + (row as f32)*BASE_CELL + ((j as f32) - 1.0)*SAMPLE_DIST;
+			if let Ok((h, dx, dy)) = maps.lookup_with_gradient(&c) {
+			    m = m.max(h + 0.5*SAMPLE_DIST*(dx.abs() + dy.abs()));
+			}
+		    }
+		}
+		*max = m;
+	    }
+
+	    res.push((row, v));
+	}
+
+	res
+    }
+
+    pub fn levels(&self) -> usize {
+	self.levels.len()
+    }
+
+    // Look up cell containing the coordinate at the given level. Returns
+    // the maximum height and the cell bounds (e_min, n_min, e_max, n_max),
+    // or None if the coordinate is outside the pyramid.
+    pub fn cell(&self, level: usize, c: &Coord) -> Option<(f32, [f32; 4])> {
+	let l = &self.levels[level];
+	let fe = (c.e - self.e0)/l.cell;
+	let fn_ = (c.n - self.n0)/l.cell;
+
+	if fe < 0.0 || fn_ < 0.0 {
+	    return None;
+	}
+
+	let col = fe as usize;
+	let row = fn_ as usize;
+
+	if col >= l.cols || row >= l.rows {
+	    return None;
+	}
+
+	let ce = self.e0 + (col as f32)*l.cell;
+	let cn = self.n0 + (row as f32)*l.cell;
+
+	Some((l.get(col, row), [ce, cn, ce + l.cell, cn + l.cell]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Level {
+	Level {
+	    cell: BASE_CELL,
+	    cols: 3,
+	    rows: 3,
+	    max: vec![1.0, 5.0, 2.0,
+		      3.0, 4.0, 9.0,
+		      7.0, 0.0, 6.0],
+	}
+    }
+
+    #[test]
+    fn cell_is_max_of_cells_below() {
+	let above = base().reduce();
+
+	assert_eq!((above.cols, above.rows), (2, 2));
+	assert_eq!(above.cell, 2.0*BASE_CELL);
+	// The cells at the edge cover the cells that are left
+	assert_eq!(above.max, [5.0, 9.0, 7.0, 6.0]);
+    }
+
+    #[test]
+    fn top_level_is_max_of_all() {
+	let mut level = base();
+	while level.cols > 1 || level.rows > 1 {
+	    level = level.reduce();
+	}
+
+	assert_eq!(level.max, [9.0]);
+    }
+
+    #[test]
+    fn cell_lookup() {
+	let pyramid = Pyramid {
+	    e0: 0.0,
+	    n0: 0.0,
+	    levels: vec![base()],
+	};
+
+	// Column 2, row 1
+	let mut c = Coord::from_polar(0.0, 0.0);
+	c.e = 2.5*BASE_CELL;
+	c.n = 1.5*BASE_CELL;
+	let (max, bounds) = pyramid.cell(0, &c).unwrap();
+	assert_eq!(max, 9.0);
+	assert_eq!(bounds, [2.0*BASE_CELL, BASE_CELL, 3.0*BASE_CELL, 2.0*BASE_CELL]);
+
+	c.e = -1.0;
+	assert!(pyramid.cell(0, &c).is_none());
+	c.e = 3.0*BASE_CELL;
+	assert!(pyramid.cell(0, &c).is_none());
+    }
+}
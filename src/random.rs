@@ -0,0 +1,117 @@
+// Deterministic random numbers. The random numbers of the renderer are
+// derived from the seed in config and what they are used for, e.g. a
+// pixel, so that a render gives the same image every time, whatever the
+// number of threads and the order of the pixels.
+use crate::config::CONFIG;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+// Fractional part of the golden ratio
+const GOLDEN_RATIO: f32 = 0.618034;
+
+// Finalizer of the SplitMix64 generator, which mixes all bits of a number
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Hash of a seed and a list of values
+fn seeded_hash(seed: u64, values: &[u64]) -> u64 {
+    let mut h = mix(seed);
+
+    for v in values {
+	h = mix(h ^ mix(v.wrapping_add(0x9e3779b97f4a7c15)));
+    }
+
+    h
+}
+
+// Hash of the seed in config and a list of values
+pub fn hash(values: &[u64]) -> u64 {
+    seeded_hash(CONFIG.seed, values)
+}
+
+// Random number from 0 to 1 (exclusive) for a list of values
+pub fn uniform(values: &[u64]) -> f32 {
+    ((hash(values) >> 40) as f32)/((1u64 << 24) as f32)
+}
+
+// Random number generator for a list of values. The algorithm is given,
+// unlike StdRng, which may change between versions of rand and give other
+// images from the same seed.
+pub fn generator(values: &[u64]) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(hash(values))
+}
+
+/*
+Stratified points in the unit square, for n samples of e.g. reflection
+rays. The first coordinate has one point in each of n equal strata, and the
+second is spread evenly by the golden ratio from a random start. This gives
+less noise than independent random points.
+ */
+pub struct Stratified {
+    n: u16,
+    start: f32,
+}
+
+impl Stratified {
+    pub fn new(rng: &mut impl Rng, n: u16) -> Self {
+	Self {
+	    n: n.max(1),
+	    start: rng.random::<f32>(),
+	}
+    }
+
+    // Point i of n
+    pub fn point(&self, rng: &mut impl Rng, i: u16) -> [f32; 2] {
+	[((i as f32) + rng.random::<f32>())/(self.n as f32),
+	 (self.start + (i as f32)*GOLDEN_RATIO).fract()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    #[test]
+    fn hash_is_deterministic() {
+	assert_eq!(seeded_hash(0, &[1, 2, 3]), seeded_hash(0, &[1, 2, 3]));
+	// Fixed values, so that a change of the hash, which changes the
+	// images from a seed, is noticed
+	assert_eq!(seeded_hash(0, &[]), mix(0));
+	assert_eq!(mix(1), 0x5692161d100b05e5);
+    }
+
+    #[test]
+    fn generator_is_pinned() {
+	// The first number from a seed, which must not change with the
+	// version of rand
+	let mut rng = ChaCha8Rng::seed_from_u64(seeded_hash(0, &[1]));
+	assert_eq!(rng.next_u64(), 17992242834176970667);
+    }
+
+    #[test]
+    fn hash_depends_on_seed_and_values() {
+	let h = seeded_hash(0, &[1, 2]);
+
+	assert_ne!(h, seeded_hash(1, &[1, 2]));
+	assert_ne!(h, seeded_hash(0, &[2, 1]));
+	assert_ne!(h, seeded_hash(0, &[1, 2, 0]));
+    }
+
+    #[test]
+    fn stratified_points_in_strata() {
+	let mut rng = ChaCha8Rng::seed_from_u64(seeded_hash(0, &[]));
+	let n = 8;
+	let points = Stratified::new(&mut rng, n);
+
+	for i in 0..n {
+	    let [u, v] = points.point(&mut rng, i);
+	    assert!(u >= (i as f32)/(n as f32) && u < ((i + 1) as f32)/(n as f32));
+	    assert!((0.0..1.0).contains(&v));
+	}
+    }
+}
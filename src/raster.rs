@@ -0,0 +1,226 @@
+// Float rasters with the terrain seen in each pixel, saved along with the
+// image
+use crate::config::CONFIG;
+use crate::gbuffer::Hit;
+
+use hoydedata::{Error, Result};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::tags::Tag;
+
+// The terrain seen in a pixel, with its position in double precision. The
+// coordinate of the hit is only single precision, which is half meter steps
+// in the north, so the position is computed from the observer instead.
+#[derive(Clone, Copy)]
+pub struct Surface {
+    pub hit: Hit,
+    pub e: f64,
+    pub n: f64,
+}
+
+// The data of a raster
+#[derive(Clone, Copy, PartialEq)]
+pub enum Layer {
+    // Distance (meters) from the observer to the terrain, along the ground
+    Depth,
+    // Easting, northing and height of the terrain
+    Position,
+    // Unit normal vector of the terrain surface (east, north, up)
+    Normal,
+}
+
+impl Layer {
+    pub fn from(name: &str) -> Result<Layer> {
+	match name {
+	    "depth" => Ok(Layer::Depth),
+	    "position" => Ok(Layer::Position),
+	    "normal" => Ok(Layer::Normal),
+	    _ => Err(Error::Generic(format!("Unknown raster {}", name))),
+	}
+    }
+
+    // The layers listed in config, separated by commas
+    pub fn from_config() -> Result<Vec<Layer>> {
+	CONFIG.rasters.split(',')
+	    .map(|name| name.trim())
+	    .filter(|name| !name.is_empty())
+	    .map(Layer::from)
+	    .collect()
+    }
+
+    pub fn name(&self) -> &'static str {
+	match self {
+	    Layer::Depth => "depth",
+	    Layer::Position => "position",
+	    Layer::Normal => "normal",
+	}
+    }
+
+    pub fn channels(&self) -> usize {
+	match self {
+	    Layer::Depth => 1,
+	    _ => 3,
+	}
+    }
+
+    fn values(&self, surface: &Surface) -> [f64; 3] {
+	let hit = &surface.hit;
+
+	match self {
+	    Layer::Depth => [hit.dist as f64, 0.0, 0.0],
+	    Layer::Position => [surface.e, surface.n, hit.height as f64],
+	    Layer::Normal => {
+		// Water is level, whatever the gradient of the maps is
+		if hit.water.is_some() {
+		    return [0.0, 0.0, 1.0];
+		}
+
+		let (dhx, dhy) = (hit.dhx as f64, hit.dhy as f64);
+		let l = (dhx*dhx + dhy*dhy + 1.0).sqrt();
+		[-dhx/l, -dhy/l, 1.0/l]
+	    },
+	}
+    }
+}
+
+/*
+Rasters of the terrain hit in each pixel, as given by the rasters parameter
+in config. Pixels showing the sky have the nodata value in all channels. The
+rasters are saved as float TIFFs, with the nodata value in the GDAL nodata
+tag. The position raster has 64 bit floats, since a 32 bit float has steps
+of up to 0.5 m at the northings of Norway. The others have 32 bit floats.
+ */
+pub struct Rasters {
+    width: u32,
+    height: u32,
+    layers: Vec<(Layer, Vec<f64>)>,
+}
+
+impl Rasters {
+    // Rasters of the layers in config, or None if there are none
+    pub fn from_config(width: u32, height: u32) -> Result<Option<Rasters>> {
+	let layers = Layer::from_config()?;
+	if layers.is_empty() {
+	    return Ok(None);
+	}
+
+	let n = (width*height) as usize;
+
+	Ok(Some(Rasters {
+	    width,
+	    height,
+	    layers: layers.into_iter()
+		.map(|l| (l, vec![CONFIG.nodata as f64; n*l.channels()]))
+		.collect(),
+	}))
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, surface: Option<Surface>) {
+	let i = (y*self.width + x) as usize;
+
+	for (layer, data) in self.layers.iter_mut() {
+	    let c = layer.channels();
+	    let values = match &surface {
+		Some(s) => layer.values(s),
+		None => [CONFIG.nodata as f64; 3],
+	    };
+
+	    data[i*c..(i + 1)*c].copy_from_slice(&values[..c]);
+	}
+    }
+
+    fn write(&self, path: &PathBuf, layer: Layer, data: &[f64])
+	     -> tiff::TiffResult<()> {
+	let mut tiff = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+	let nodata = CONFIG.nodata.to_string();
+	let single: Vec<f32> = match layer {
+	    Layer::Position => Vec::new(),
+	    _ => data.iter().map(|&v| v as f32).collect(),
+	};
+
+	match layer {
+	    Layer::Depth => {
+		let mut image = tiff.new_image::<colortype::Gray32Float>(
+		    self.width, self.height)?;
+		image.encoder().write_tag(Tag::GdalNodata, nodata.as_str())?;
+		image.write_data(&single)
+	    },
+	    Layer::Position => {
+		let mut image = tiff.new_image::<colortype::RGB64Float>(
+		    self.width, self.height)?;
+		image.encoder().write_tag(Tag::GdalNodata, nodata.as_str())?;
+		image.write_data(data)
+	    },
+	    Layer::Normal => {
+		let mut image = tiff.new_image::<colortype::RGB32Float>(
+		    self.width, self.height)?;
+		image.encoder().write_tag(Tag::GdalNodata, nodata.as_str())?;
+		image.write_data(&single)
+	    },
+	}
+    }
+
+    // Save the rasters, each to the path given for the name of its layer.
+    // Returns the names of the saved files.
+    pub fn save(&self, path: impl Fn(&str) -> PathBuf) -> Vec<String> {
+	let mut files = Vec::new();
+
+	for (layer, data) in &self.layers {
+	    let p = path(layer.name());
+	    self.write(&p, *layer, data).unwrap();
+	    files.push(p.to_string_lossy().to_string());
+	}
+
+	files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hoydedata::Coord;
+
+    fn surface(water: Option<f32>) -> Surface {
+	let hit = Hit {
+	    coord: Coord::from_polar(0.0, 0.0),
+	    dist: 1234.5,
+	    height: 456.0,
+	    dhx: 0.3,
+	    dhy: -0.4,
+	    water,
+	};
+
+	Surface { hit, e: 123456.25, n: 6912345.75 }
+    }
+
+    #[test]
+    fn depth_and_position() {
+	let s = surface(None);
+
+	assert_eq!(Layer::Depth.values(&s)[0], 1234.5);
+	// The position keeps the precision of the surface, not the hit
+	assert_eq!(Layer::Position.values(&s), [123456.25, 6912345.75, 456.0]);
+    }
+
+    #[test]
+    fn normal_of_terrain_and_water() {
+	let n = Layer::Normal.values(&surface(None));
+	assert!((n[0]*n[0] + n[1]*n[1] + n[2]*n[2] - 1.0).abs() < 1e-9);
+	// Facing down the slope
+	assert!(n[0] < 0.0 && n[1] > 0.0 && n[2] > 0.0);
+	assert!((n[0]/n[2] + 0.3).abs() < 1e-6 && (n[1]/n[2] - 0.4).abs() < 1e-6);
+
+	assert_eq!(Layer::Normal.values(&surface(Some(450.0))), [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn layer_names() {
+	for layer in [Layer::Depth, Layer::Position, Layer::Normal] {
+	    assert!(Layer::from(layer.name()).ok() == Some(layer));
+	}
+
+	assert!(Layer::from("height").is_err());
+    }
+}
@@ -1,35 +1,154 @@
 // extern crate image;
-use crate::config::CONFIG;
+use crate::config::{CONFIG, UTM_ZONE};
 use crate::canvas::Canvas;
 use crate::progress::Progress;
 use crate::color::*;
+use crate::atmosphere::Atmosphere;
+use crate::sky::Sky;
+use crate::moon;
+use crate::maps::Heights;
+use crate::detail::Detail;
+use crate::pyramid::Pyramid;
+use crate::lakes::{Lakes, LAKE_MAX_GRADIENT};
+use crate::sampling::Sampling;
+use crate::projection::{self, Projection, Kind};
+use crate::stereo::{Stereo, Eye};
+use crate::view::View;
+use crate::animation;
+use crate::random::{self, Stratified};
+use crate::gbuffer::{GBuffer, Hit, Hits, Sample, Strips};
+use crate::raster::{Layer, Surface};
+use crate::water::{self, Bathymetry, Facets, WaterMask, WaterType, WaveField};
 
 use hoydedata::{Atlas, MsgSender, MsgReceiver, Coord, Coord3, Error, Result};
 use std::f32::consts::PI;
 use chrono::{DateTime};
 use geomorph::*;
-use rand::Rng;
-use std::thread::spawn;
+use rand_chacha::ChaCha8Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{available_parallelism, spawn};
+use std::time::Instant;
 use crossbeam_channel::{select, unbounded, Sender, Receiver};
 use std::io::{stdin, stdout};
 use std::io::prelude::*;
 
-const R_EARTH: f32 = 6371000.0;
+pub const R_EARTH: f32 = 6371000.0;
+
+// Longest step (meters) when tracing rays in the pyramid
+const PYRAMID_STEP: f32 = 5.0;
+
+// Height above ground and start distance (meters) of shadow rays
+const SHADOW_RAY_OFFSET: f32 = 2.0;
+const SHADOW_RAY_START: f32 = 10.0;
+
+// Smallest sun glint on water which is worth tracing a shadow ray for
+const GLINT_MIN: f32 = 0.001;
+
+// Slope of the bottom from the shore, for the depth of the water near the
+// shore
+const SHORE_SLOPE: f32 = 0.1;
+
+// Number of columns in each strip of the image handed to a render thread
+const STRIP_WIDTH: u32 = 16;
+
+// Number of frames of an animation the terrain data is made for at a time
+const TERRAIN_FRAMES: usize = 10;
+
+// Height of ray above sea level at distance r. The formula includes ground
+// curvature.
+fn ray_height(r: f32, v_angle: f32, observer_height: f32) -> f32 {
+    let beta = r/R_EARTH;
+    let alfa = beta + v_angle;
+
+    (R_EARTH + observer_height)*(beta.cos() + beta.sin()*alfa.tan()) - R_EARTH
+}
+
+// Lowest height of ray between distance r0 and r1. The ray is lowest where
+// it is parallel with the ground, or at one of the ends.
+fn ray_min_height(r0: f32, r1: f32, v_angle: f32, observer_height: f32) -> f32 {
+    let mut h = ray_height(r0, v_angle, observer_height)
+	.min(ray_height(r1, v_angle, observer_height));
+    let r_low = -v_angle*R_EARTH;
+
+    if r_low > r0 && r_low < r1 {
+	h = h.min(ray_height(r_low, v_angle, observer_height));
+    }
+
+    h
+}
+
+// Unit vector of a direction given by directional and vertical angle
+fn direction_vector(h_angle: f32, v_angle: f32) -> Coord3 {
+    Coord3::new(h_angle.cos()*v_angle.cos(), h_angle.sin()*v_angle.cos(),
+		v_angle.sin())
+}
 
 pub enum RenderOutput {
-    DrawPixel(u32, u32, Color),
+    DrawPixel(Eye, u32, u32, Color),
+    DrawHit(Eye, u32, u32, Option<Surface>),
     IncProgress(u64),
+    SaveFrame(usize),
     Finish,
 }
 
+// Traced samples of each strip of the image, by eye and start column
+type HitCache = Mutex<Strips>;
+
 pub type ProgressSender = Sender<RenderOutput>;
 pub type ProgressReceiver = Receiver<RenderOutput>;
 
-pub fn handle_output(prx: ProgressReceiver, mrx: MsgReceiver) {
-    let mut canvas = Canvas::new(CONFIG.width, CONFIG.height);
+// Reports from the render threads of finished strips, or of an error
+type DoneSender = Sender<std::result::Result<(), String>>;
+
+/*
+Terrain data of the views of some frames, made before their strips are
+rendered: the heights of the 1m maps, the max-height pyramid and the
+lakes. The data covers the rectangle from c_min to c_max, and is shared by
+the render threads.
+ */
+#[derive(Default)]
+struct Terrain {
+    c_min: Option<Coord>,
+    c_max: Option<Coord>,
+    detail: Option<Arc<Detail>>,
+    pyramid: Option<Arc<Pyramid>>,
+    lakes: Option<Arc<Lakes>>,
+}
+
+// A strip of the image to render: frame, eye, first column, and the
+// terrain data of the frame
+struct Strip {
+    frame: usize,
+    eye: Eye,
+    x: u32,
+    terrain: Arc<Terrain>,
+}
+
+// Data shared by the render threads for all frames
+#[derive(Clone)]
+struct Shared {
+    views: Arc<Vec<View>>,
+    water_mask: Option<Arc<WaterMask>>,
+    bathymetry: Option<Arc<Bathymetry>>,
+    cache: Option<Arc<HitCache>>,
+    ptx: ProgressSender,
+    mtx: MsgSender,
+    dtx: DoneSender,
+}
+
+// Handle output from the render threads. An animation has a number of
+// frames, which are saved one by one. A single image is saved when
+// rendering is finished.
+pub fn handle_output(prx: ProgressReceiver, mrx: MsgReceiver,
+		     frames: usize, animated: bool) -> Result<()> {
+    let stereo = Stereo::from_config()?;
+    let (width, height) = stereo.image_size();
+    let mut canvas = Canvas::new(width, height)?;
 
     let progress = Progress::new();
-    progress.set_length(CONFIG.height.into());
+    let columns = (CONFIG.width as u64)*(stereo.eyes().len() as u64);
+    progress.set_length(columns*(frames as u64));
 
     'outer: loop {
         select! {
@@ -48,10 +167,21 @@ pub fn handle_output(prx: ProgressReceiver, mrx: MsgReceiver) {
                 match recv_prx {
                     Ok(ro) => {
                         match ro {
-                            RenderOutput::DrawPixel(x, y, color) =>
-                                canvas.draw_pixel(x, y, color),
-                            RenderOutput::IncProgress(i) =>
-                                progress.inc(i),
+                            RenderOutput::DrawPixel(eye, x, y, color) =>
+                                canvas.draw_eye_pixel(eye, x, y, color),
+                            RenderOutput::DrawHit(eye, x, y, surface) =>
+                                canvas.draw_eye_hit(eye, x, y, surface),
+                            RenderOutput::IncProgress(i) => {
+                                canvas.present();
+                                progress.inc(i);
+                            },
+                            RenderOutput::SaveFrame(frame) => {
+                                if let Some(file) = canvas.save_frame(frame) {
+                                    progress.println(&format!(
+                                        "Saved frame {}/{} to {}",
+                                        frame + 1, frames, file));
+                                }
+                            },
                             RenderOutput::Finish =>
                                 break 'outer,
                         }
@@ -66,8 +196,14 @@ pub fn handle_output(prx: ProgressReceiver, mrx: MsgReceiver) {
         }
     }
 
-    canvas.save();
-    progress.println(&format!("Saved image to {}", CONFIG.output));
+    if !animated {
+	for file in canvas.save() {
+	    progress.println(&format!("Saved image to {}", file));
+	}
+    }
+    else if let Some(file) = canvas.save_animation() {
+	progress.println(&format!("Saved animation to {}", file));
+    }
     progress.finish();
 
     if !CONFIG.headless {
@@ -80,14 +216,25 @@ pub fn handle_output(prx: ProgressReceiver, mrx: MsgReceiver) {
 	let _ = stdin().read(&mut [0u8]).unwrap();        
     }
 
+    Ok(())
 }
 
 pub struct Renderer {
     sun_ray: Coord3,
+    atmosphere: Atmosphere,
+    sky: Sky,
+    sun_h_angle: f32,
+    sun_v_angle: f32,
+    moon_ray: Coord3,
+    moon_h_angle: f32,
+    moon_v_angle: f32,
+    view: View,
+    observer: Coord,
+    eye: Eye,
     observer_height: f32,
-    horizontal_middle_angle: f32,
-    vertical_middle_angle: f32,
+    centre_height: f32,
     vertical_angle_corr: f32,
+    r1: f32,
     r10: f32,
     dr_min: f32,
     dr_max: f32,
@@ -95,203 +242,546 @@ pub struct Renderer {
     dr_min_range: f32,
     dr_max_range: f32,
     sea_min_reflection_angle: f32,
-    focus_depth: f32,
-    atlas1: Atlas,
-    atlas10: Atlas,
+    facets: Facets,
+    waves: WaveField,
+    projection: Projection,
+    atlas10: Box<dyn Heights>,
+    detail: Option<Arc<Detail>>,
+    pyramid: Option<Arc<Pyramid>>,
+    lakes: Option<Arc<Lakes>>,
+    water_mask: Option<Arc<WaterMask>>,
+    bathymetry: Option<Arc<Bathymetry>>,
+    water_type: WaterType,
+    lake_type: WaterType,
+    sampling: Sampling,
+    rng: ChaCha8Rng,
     ptx: Option<ProgressSender>,
 }
 
 impl Renderer {
-    pub fn sun_position(time: &str, pos: Coord) -> Result<(f32, f32)> {
+    // Parse time and convert position to geographic coordinates. Returns
+    // (unix time in ms, latitude, longitude).
+    fn time_and_place(time: &str, pos: Coord) -> Result<(i64, f64, f64)> {
 	let utm = Utm::new(
-	    pos.e as f64, pos.n as f64, true, 33, 'W', false);
+	    pos.e as f64, pos.n as f64, true, UTM_ZONE as i32, 'W', false);
 	let gc : geomorph::Coord = utm.into();
 
 	let res = DateTime::parse_from_str(&time, "%Y-%m-%dT%H:%M:%S%z");
 	if let Ok(dt) = res {
-	    let ep = dt.timestamp_millis();
-	    let pos = sun::pos(ep, gc.lat, gc.lon);
-	    let az  = pos.azimuth;
-	    let alt = pos.altitude;
-
-	    Ok((az as f32, alt as f32))
+	    Ok((dt.timestamp_millis(), gc.lat, gc.lon))
 	}
 	else {
 	    return Err(Error::Generic(time.to_string()).into());
 	}
     }
 
-    pub fn new(atlas1: Atlas, atlas10: Atlas, ptx: Option<ProgressSender>)
-               -> Result<Self> {
-	// Pre-calculate as much as we can before start.
+    pub fn sun_position(time: &str, pos: Coord) -> Result<(f32, f32)> {
+	let (ep, lat, lon) = Renderer::time_and_place(time, pos)?;
+	let pos = sun::pos(ep, lat, lon);
+	let az  = pos.azimuth;
+	let alt = pos.altitude;
 
-	// Calculate sun ray directional unit vector based on horizontal and
-	// vertical angle
-	let (az, alt) = Renderer::sun_position(&CONFIG.time, CONFIG.observer)?;
-	let sun_ray = Coord3::new(0.0, 1.0, 0.0).rot_e(alt).rot_h(-az);
+	Ok((az as f32, alt as f32))
+    }
 
-        // Observer ground height
-	let observer_height = atlas10.lookup(&CONFIG.observer)? +
-	    CONFIG.observer_height_offset;
+    // Position of the moon (azimuth and altitude), and the illuminated
+    // fraction of the moon disc.
+    pub fn moon_position(time: &str, pos: Coord) -> Result<(f32, f32, f32)> {
+	let (ep, lat, lon) = Renderer::time_and_place(time, pos)?;
+	let (az, alt) = moon::pos(ep, lat, lon);
+	let illumination = moon::illumination(ep);
 
-        // Target ground height
-	let target_height = atlas10.lookup(&CONFIG.target)? +
-	    CONFIG.target_height_offset;
+	Ok((az as f32, alt as f32, illumination as f32))
+    }
 
-        // Middle directional angle
-	let diff = CONFIG.target - CONFIG.observer;
-	let mut h_middle_angle;
+    // Directional angle of a vector
+    fn direction(diff: Coord) -> f32 {
+	let mut angle;
         if diff.e.abs() > diff.n.abs() {
-            h_middle_angle = (diff.n/diff.e).atan();
+            angle = (diff.n/diff.e).atan();
             if diff.e < 0.0 {
-                if h_middle_angle <= 0.0 {
-                    h_middle_angle += PI;
+                if angle <= 0.0 {
+                    angle += PI;
 		}
                 else {
-                    h_middle_angle = h_middle_angle - PI;
+                    angle = angle - PI;
 		}
 	    }
 	}
         else {
-            h_middle_angle = 0.5*PI - (diff.e/diff.n).atan();
+            angle = 0.5*PI - (diff.e/diff.n).atan();
 	    if diff.n < 0.0 {
-		h_middle_angle = h_middle_angle - PI;
+		angle = angle - PI;
 	    }
 	}
 
-        // Middle vertical angle. The formula includes ground curvature
-	// Horizontal distance from observer to target at observer height.
-	let beta: f64 = ((CONFIG.target - CONFIG.observer).abs()/R_EARTH).into();
-	let ro: f64 = (observer_height + R_EARTH).into();
-	let rt: f64 = (target_height + R_EARTH).into();
-	let x = ro*beta.sin();
-	let y = (ro*ro - x*x).sqrt();
-	let v_middle_angle = ((rt - y)/x).atan() - beta;
+	angle
+    }
+
+    pub fn new(atlas10: Atlas, ptx: Option<ProgressSender>) -> Result<Self> {
+	Renderer::with_view(Box::new(atlas10), ptx, &View::from_config())
+    }
+
+    // Renderer for a given view. The 10m maps can be passed on from the
+    // renderer of the previous view with into_atlas(), so that the loaded
+    // maps are kept.
+    pub fn with_view(atlas10: Box<dyn Heights>, ptx: Option<ProgressSender>,
+		     view: &View) -> Result<Self> {
+	// Pre-calculate as much as we can before start.
+
+	// Calculate sun ray directional unit vector based on horizontal and
+	// vertical angle
+	let (az, alt) = Renderer::sun_position(&view.time, view.observer)?;
+	let sun_ray = Coord3::new(0.0, 1.0, 0.0).rot_e(alt).rot_h(-az);
+
+	// Moon ray and phase
+	let (m_az, m_alt, illumination) = Renderer::moon_position(
+	    &view.time, view.observer)?;
+	let moon_ray = Coord3::new(0.0, 1.0, 0.0).rot_e(m_alt).rot_h(-m_az);
+
+	let atmosphere = Atmosphere::new(sun_ray, alt,
+					 moon_ray, m_alt, illumination);
+	let sky = Sky::new(sun_ray, alt, atmosphere.sun_color(),
+			   moon_ray, atmosphere.moon_color());
+
+	// Horizontal direction of the sun and the moon, as directional angle
+	let sun_h_angle = sun_ray.dot(Coord3::new(0.0, 1.0, 0.0))
+	    .atan2(sun_ray.dot(Coord3::new(1.0, 0.0, 0.0)));
+	let moon_h_angle = moon_ray.dot(Coord3::new(0.0, 1.0, 0.0))
+	    .atan2(moon_ray.dot(Coord3::new(1.0, 0.0, 0.0)));
+
+        // Observer ground height
+	let observer_height = Renderer::observer_height(atlas10.as_ref(), view)?;
+
+        // Middle directional and vertical angle
+	let kind = Kind::from_config()?;
+	let h_middle_angle = Renderer::h_middle_angle(kind, view);
+	let v_middle_angle = Renderer::v_middle_angle(atlas10.as_ref(), kind,
+						      view, observer_height)?;
 
 	// Vertical angle correction. The direction towards the horizon is
 	// lower than the tangent direction from observer. We calculate the
 	// difference.
 	let v_angle_corr = (R_EARTH/(R_EARTH + observer_height)).acos();
 
+	let roll = CONFIG.roll.unwrap_or(0.0).to_radians();
+	let projection = Projection::new(kind, h_middle_angle, v_middle_angle as f32,
+					 roll, view.width_angle);
+
+	// The cube faces are square, side by side
+	if projection.kind() == Kind::Cubemap && CONFIG.width != 6*CONFIG.height {
+	    return Err(Error::Generic(
+		"Cubemap width must be 6 times the height".to_string()));
+	}
+
+	// The full sphere covers 360 by 180 degrees, with square pixels
+	if projection.kind() == Kind::Equirectangular && CONFIG.width != 2*CONFIG.height {
+	    return Err(Error::Generic(
+		"Equirectangular width must be twice the height".to_string()));
+	}
+
+        // Depth of viewer from image
+        let d = projection.focus_depth();
+
 	let dr_min = 0.9;
 	let dr_max = 30.0;
-	let dr_factor = (CONFIG.width as f32)/(3.0*CONFIG.width_angle.tan());
+	let dr_factor = 2.0*d/3.0;
 	let dr_min_range = dr_min*dr_factor;
 	let dr_max_range = dr_max*dr_factor;
 
-        // Depth of viewer from image
-        let d = (CONFIG.width as f32)/(2.0*CONFIG.width_angle.tan());
-
-	// Distance when we switch from 1m to 10m samples. We do this when
-	// the angle of one pixle is approximately 8m
-	let r10 = 8.0*d;
+	let sampling = Sampling::from_config()?;
+	let (r1, r10) = Renderer::atlas_range(kind, view.width_angle)?;
 
 	Ok(Self {
-	    sun_ray: sun_ray,
-	    observer_height: observer_height,
-	    horizontal_middle_angle: h_middle_angle,
-	    vertical_middle_angle: (v_middle_angle as f32),
+	    sun_ray,
+	    atmosphere,
+	    sky,
+	    sun_h_angle,
+	    sun_v_angle: alt,
+	    moon_ray,
+	    moon_h_angle,
+	    moon_v_angle: m_alt,
+	    view: view.clone(),
+	    observer: view.observer,
+	    eye: Eye::Centre,
+	    observer_height,
+	    centre_height: observer_height,
 	    vertical_angle_corr: (v_angle_corr as f32),
-	    r10: r10,
-	    dr_min: dr_min,
-	    dr_max: dr_max,
-	    dr_factor: dr_factor,
-	    dr_min_range: dr_min_range,
-	    dr_max_range: dr_max_range,
+	    r1,
+	    r10,
+	    dr_min,
+	    dr_max,
+	    dr_factor,
+	    dr_min_range,
+	    dr_max_range,
 	    sea_min_reflection_angle: 0.5_f32.to_radians(),
-	    focus_depth: d,
-            atlas1: atlas1,
-            atlas10: atlas10,
-            ptx: ptx,
+	    facets: Facets::from_config(),
+	    waves: WaveField::from_config(),
+	    projection,
+            atlas10,
+            detail: None,
+            pyramid: None,
+            lakes: None,
+            water_mask: None,
+            bathymetry: None,
+            water_type: WaterType::from(&CONFIG.water_type)?,
+            lake_type: WaterType::from(&CONFIG.lake_type)?,
+            sampling,
+            rng: random::generator(&[]),
+            ptx,
 	})
     }
 
-    fn land_color(&mut self,
-		  dist: f32,
-		  total_dist: f32,
-		  angle: f32,
-		  coord: Coord) -> Color {
+    /*
+    Render the view from an eye of a stereo pair. The eye is moved sideways
+    from the observer, perpendicular to the view direction. The eyes have
+    the same height and view direction as the observer, so that the views
+    only differ by the horizontal parallax, except where the ground at an
+    eye is higher. The eye is then lifted observer_height_offset above the
+    ground there, as the observer.
+     */
+    pub fn set_eye(&mut self, eye: Eye) -> Result<()> {
+	let right = self.projection.h_middle() - 0.5*PI;
+
+	self.eye = eye;
+	self.observer = Coord::from_polar(eye.offset(), right) + self.view.observer;
+
+	let ground = self.atlas10.lookup(&self.observer)?;
+	self.observer_height = self.centre_height
+	    .max(ground + CONFIG.observer_height_offset);
+
+	Ok(())
+    }
+
+    // Take the 10m maps back, for the renderer of the next view
+    pub fn into_atlas(self) -> Box<dyn Heights> {
+	self.atlas10
+    }
+
+    // Use the heights of the 1m maps looked up before the render
+    pub fn set_detail(&mut self, detail: Arc<Detail>) {
+	self.detail = Some(detail);
+    }
+
+    // Use max-height pyramid for skipping empty space along the rays
+    pub fn set_pyramid(&mut self, pyramid: Arc<Pyramid>) {
+	self.pyramid = Some(pyramid);
+    }
+
+    // Use the lakes found in the view for the heights of lake surfaces
+    pub fn set_lakes(&mut self, lakes: Arc<Lakes>) {
+	self.lakes = Some(lakes);
+    }
+
+    // Use water mask for finding lakes
+    pub fn set_water_mask(&mut self, water_mask: Arc<WaterMask>) {
+	self.water_mask = Some(water_mask);
+    }
+
+    // Use bathymetry for the depth of the water
+    pub fn set_bathymetry(&mut self, bathymetry: Arc<Bathymetry>) {
+	self.bathymetry = Some(bathymetry);
+    }
+
+    // Directional angle of the middle of the image. The azimuth is given
+    // in config (degrees from north, clockwise), or it is the direction
+    // towards the target. A full circle panorama is centered on south by
+    // default, so that the seam is at north.
+    fn h_middle_angle(kind: Kind, view: &View) -> f32 {
+	if let Some(azimuth) = CONFIG.azimuth {
+	    return 0.5*PI - azimuth.to_radians();
+	}
+
+	if projection::full_circle(kind, view.width_angle) {
+	    return -0.5*PI;
+	}
+
+	Renderer::direction(view.target - view.observer)
+    }
+
+    // Height of the observer above sea level, at least observer_height of
+    // the view above the ground
+    fn observer_height(atlas10: &dyn Heights, view: &View) -> Result<f32> {
+	let mut observer_height = atlas10.lookup(&view.observer)?;
+	if let Some(h) = view.observer_height {
+	    observer_height = observer_height.max(h);
+	}
+
+	Ok(observer_height + CONFIG.observer_height_offset)
+    }
+
+    /*
+    Vertical angle (radians) of the middle of the image. The pitch is given
+    in config, or calculated from the target. With the azimuth in config,
+    the camera does not face the target, and is level by default. A full
+    circle panorama has the horizon in the middle by default, and does not
+    use the target.
+     */
+    fn v_middle_angle(atlas10: &dyn Heights, kind: Kind, view: &View,
+		      observer_height: f32) -> Result<f64> {
+	if let Some(pitch) = CONFIG.pitch {
+	    return Ok(pitch.to_radians() as f64);
+	}
+
+	if CONFIG.azimuth.is_some() ||
+	    projection::full_circle(kind, view.width_angle) {
+	    return Ok(0.0);
+	}
+
+        // Target ground height
+	let target_height = atlas10.lookup(&view.target)? +
+	    CONFIG.target_height_offset;
+
+        // Middle vertical angle. The formula includes ground curvature
+	// Horizontal distance from observer to target at observer height.
+	let beta: f64 = ((view.target - view.observer).abs()/R_EARTH).into();
+	let ro: f64 = (observer_height + R_EARTH).into();
+	let rt: f64 = (target_height + R_EARTH).into();
+	let x = ro*beta.sin();
+	let y = (ro*ro - x*x).sqrt();
+
+	Ok(((rt - y)/x).atan() - beta)
+    }
+
+    // Horizontal angle of view from the middle to the edge of the image of
+    // a view, with the pitch of the view
+    fn half_width_angle(atlas10: &dyn Heights, kind: Kind, view: &View)
+			-> Result<f32> {
+	let observer_height = Renderer::observer_height(atlas10, view)?;
+	let pitch = Renderer::v_middle_angle(atlas10, kind, view,
+					     observer_height)? as f32;
+
+	Ok(projection::half_width_angle(kind, view.width_angle, pitch).min(PI))
+    }
+
+    // Camera orientation: azimuth (degrees from north, clockwise), pitch
+    // and roll (degrees). These can be put in config instead of the target.
+    pub fn orientation(&self) -> (f32, f32, f32) {
+	let (h_middle, v_middle) = self.projection.middle();
+	let azimuth = (90.0 - h_middle.to_degrees()).rem_euclid(360.0);
 
-	// Calculate land color
-	let height;
-	let dhx;
-	let dhy;
+	(azimuth, v_middle.to_degrees(), self.projection.roll().to_degrees())
+    }
+
+    /*
+    Distances where the transition from 1m to 10m samples starts and ends.
+    We switch when the footprint of a sample on the ground, i.e. the
+    distance between neighbour samples, is atlas_footprint meters. With
+    supersampling, the samples are closer, and we switch later.
+     */
+    fn atlas_range(kind: Kind, width_angle: f32) -> Result<(f32, f32)> {
+	let d = projection::focus_depth(kind, width_angle);
+	let sampling = Sampling::from_config()?;
+	let footprint = CONFIG.atlas_footprint*(sampling.nx.max(sampling.ny) as f32);
+	let blend = CONFIG.atlas_blend.clamp(0.0, 0.99);
+
+	Ok((footprint*d*(1.0 - blend), footprint*d*(1.0 + blend)))
+    }
+
+    // Blocks of the 1m maps used in the image of a view, out to where the
+    // transition to the 10m maps ends
+    pub fn detail_blocks(atlas10: &dyn Heights, view: &View)
+			 -> Result<Vec<(i32, i32)>> {
+	let kind = Kind::from_config()?;
+	let (_, r10) = Renderer::atlas_range(kind, view.width_angle)?;
+	let a = Renderer::half_width_angle(atlas10, kind, view)?;
 
-	let mut ret : Result<(f32, f32, f32)> = Err(
-            Error::Generic("Failed to get land color".to_string()).into());
+	Ok(Detail::blocks(view.observer, Renderer::h_middle_angle(kind, view), a,
+			  r10, 0.5*CONFIG.stereo_baseline.abs()))
+    }
 
-	if total_dist < self.r10 {
-	    // Try 1m resolution lookup if we are close
-	    ret = self.atlas1.lookup_with_gradient(&coord);
+    // Bounding rectangle of the terrain covered by the image of a view
+    pub fn view_bounds(atlas10: &dyn Heights, view: &View)
+		       -> Result<(Coord, Coord)> {
+	let o = view.observer;
+	let kind = Kind::from_config()?;
+	let h_middle_angle = Renderer::h_middle_angle(kind, view);
+	let a = Renderer::half_width_angle(atlas10, kind, view)?;
+	let mut c_min = o;
+	let mut c_max = o;
+
+	// Follow the arc at max_depth from one side of the image to the
+	// other. Add a margin for the parts of the arc between the points.
+	let steps = 64;
+	for i in 0..(steps + 1) {
+	    let h = h_middle_angle - a + 2.0*a*(i as f32)/(steps as f32);
+	    let c = Coord::from_polar(CONFIG.max_depth, h) + o;
+	    c_min.e = c_min.e.min(c.e);
+	    c_min.n = c_min.n.min(c.n);
+	    c_max.e = c_max.e.max(c.e);
+	    c_max.n = c_max.n.max(c.n);
 	}
 
-	if let Err(_) = ret {
-	    // Fallback to 10m resolution
-	    ret = self.atlas10.lookup_with_gradient(&coord);
+	// The eyes of a stereo pair are up to half the baseline from the
+	// observer.
+	let mut margin = 500.0;
+	if Stereo::from_config()? != Stereo::None {
+	    margin += 0.5*CONFIG.stereo_baseline.abs();
 	}
 
-	if let Ok((h, dx, dy)) = ret {
-	    dhx = dx;
-	    dhy = dy;
-	    height = h;
+	c_min.e -= margin;
+	c_min.n -= margin;
+	c_max.e += margin;
+	c_max.n += margin;
+
+	Ok((c_min, c_max))
+    }
+
+    // Look up the terrain where a ray hit land. Outside the maps, we assume
+    // sea.
+    fn hit(&mut self, coord: Coord, dist: f32, total_dist: f32) -> Hit {
+	let (height, dhx, dhy) = self.terrain(&coord, total_dist)
+	    .unwrap_or((0.0, 0.0, 0.0));
+
+	Hit {
+	    coord,
+	    dist,
+	    height,
+	    dhx,
+	    dhy,
+	    water: self.water_surface(&coord, height, dhx, dhy),
+	}
+    }
+
+    /*
+    Height of the water surface at a point of the terrain, or None if there
+    is no water. Below water_level, there is sea. Lakes are given by the
+    water mask, or found in the maps where they are flat, see Lakes. Each
+    lake has the surface height of its region. Where the water mask has
+    water outside the lakes found, the height of the terrain is used.
+    Without a mask, the point is on a lake if the terrain is flat and at
+    the height of a lake around it.
+     */
+    fn water_surface(&self, c: &Coord, height: f32, dhx: f32, dhy: f32)
+		     -> Option<f32> {
+	if height <= CONFIG.water_level {
+	    return Some(CONFIG.water_level);
+	}
+
+	let lake = self.lakes.as_ref().and_then(|l| l.level(c, height));
+
+	if let Some(mask) = &self.water_mask {
+	    return if mask.is_water(c) { Some(lake.unwrap_or(height)) } else { None };
+	}
+
+	if dhx*dhx + dhy*dhy > LAKE_MAX_GRADIENT {
+	    return None;
+	}
+
+	lake.filter(|level| (level - height).abs() <= CONFIG.lake_tolerance)
+    }
+
+    // Type of the water at a point with a water surface at a given level.
+    // The type given by the water mask comes first, also below
+    // water_level. Otherwise, there is sea of water_type below
+    // water_level, and lakes of lake_type above it.
+    fn water_type(&self, c: &Coord, water_level: f32) -> WaterType {
+	if let Some(t) = self.water_mask.as_ref().and_then(|m| m.water_type(c)) {
+	    return t;
+	}
+
+	if water_level <= CONFIG.water_level {
+	    self.water_type
 	}
 	else {
-	    // Fallback to sea
-	    dhx = 0.0;
-	    dhy = 0.0;
-	    height = 0.0;
+	    self.lake_type
 	}
+    }
 
-	// 0 = blue terrain far away
-	// 1 = sharp colors at close distance
-	let blueness = (-CONFIG.rayleigh*0.00003*dist).exp();
-	// 0 = hazy, 1 = clear
-        let whiteness = (-CONFIG.haziness*0.000002*dist).exp();
+    /*
+    Depth of the water at a point, from the bathymetry, or None if it is
+    not known. Outside the bathymetry, the depth near the shore is found
+    from the distance to the shore within shore_distance, which is found
+    for the lake grid before the render, see Lakes. The bottom slopes down
+    from the shore by SHORE_SLOPE.
+     */
+    fn water_depth(&self, c: &Coord) -> Option<f32> {
+	if let Some(depth) = self.bathymetry.as_ref().and_then(|b| b.depth(c)) {
+	    return Some(depth);
+	}
 
+	self.lakes.as_ref()
+	    .and_then(|l| l.shore_distance(c))
+	    .map(|d| SHORE_SLOPE*d)
+    }
+
+    fn land_color(&mut self,
+		  hit: &Hit,
+		  total_dist: f32,
+		  start_height: f32,
+		  h_angle: f32,
+		  angle: f32) -> Color {
+	let Hit { coord, dist, height, dhx, dhy, water } = *hit;
 	let color;
 
 	let grad = dhx*dhx + dhy*dhy;
 	
-	if height <= CONFIG.water_level {
-	    // Water surface. Continue tracing the reflected ray, using
-	    // the inverse angle corrected by curvature due to distance.
-	    let mut r_angle = dist/R_EARTH - angle;
-
-	    if r_angle < self.sea_min_reflection_angle {
-		r_angle = self.sea_min_reflection_angle;
-	    }
-
-	    // Blend reflection and flat sea color
+	if let Some(water_level) = water {
+	    // Water surface. The view direction in the local frame at the
+	    // water, where the vertical is tilted by the curvature of the
+	    // earth, as in ray_height().
+	    let delta = dist/R_EARTH;
+	    let view = water::tilt(water::direction(h_angle, angle), h_angle, delta);
+	    let r_angle = (-view[2]).asin().max(self.sea_min_reflection_angle);
+
+	    // Color of the water, lighter where it is shallow
+	    let depth = self.water_depth(&coord);
+	    let water_color = self.water_type(&coord, water_level).color(depth);
+
+	    // Blend reflection and water color. The reflected rays are
+	    // traced from random wave facets, stratified by their slope.
 	    let n = CONFIG.water_reflection_iterations;
-	    let seamix;
+	    let mut seamix = water_color;
+	    let mut glint = BLACK;
+
+	    // Slope of the waves where the view hits the water
+	    let footprint = total_dist/self.projection.focus_depth();
+	    let (slope, filtered) = self.waves.slope(&coord, footprint, h_angle,
+						     r_angle);
 
 	    if n > 0 && CONFIG.water_shininess != 0.0 {
 		let mut rcolor = BLACK;
-		let re1 = coord - CONFIG.observer;
-		let re2 = re1*(CONFIG.max_depth/re1.abs()) + coord;
-		let mut rng = rand::rng();
-		let afuzz = 0.01*CONFIG.water_ripples;
-		let range = afuzz*0.5*PI;
-
-		for _ in 0..n {
-		    let rafuzz = rng.random::<f32>()*range + r_angle*(1.0 - afuzz);
-		    let ray = self.render_ray(rafuzz, total_dist, coord,
-					      CONFIG.water_level + 1.0, re2);
-		    rcolor += self.find_color(ray, total_dist, rafuzz);
+		let points = Stratified::new(&mut self.rng, n);
+
+		for i in 0..n {
+		    let u = points.point(&mut self.rng, i);
+		    let normal = self.facets.sample(u, slope, filtered);
+		    let r = water::reflect(view, normal);
+		    let rh = r[1].atan2(r[0]);
+		    let rv = r[2].asin().max(self.sea_min_reflection_angle);
+		    let ray_end = Coord::from_polar(CONFIG.max_depth, rh) + coord;
+		    let ray = self.render_ray(rv, total_dist, coord,
+					      water_level + 1.0, ray_end,
+					      CONFIG.min_depth);
+
+		    // The sun disc is added as glint below
+		    rcolor += match ray {
+			Some(_) => self.find_color(ray, total_dist,
+						   water_level + 1.0, rh, rv),
+			None => self.sky_color_without_sun(rh, rv),
+		    };
 		}
 		rcolor = rcolor*(1.0/(n as f32));
-		seamix = SEA.blend(&rcolor, CONFIG.water_shininess);
+		seamix = water_color.blend(&rcolor, CONFIG.water_shininess);
 	    }
-	    else {
-		seamix = SEA;
+
+	    // Glint of the sun in the wave facets facing it
+	    if CONFIG.water_shininess != 0.0 && self.sun_v_angle > 0.0 {
+		let sun = water::tilt(water::direction(self.sun_h_angle, self.sun_v_angle),
+				      h_angle, delta);
+		let g = self.facets.glint(view, sun, slope, filtered)*
+		    CONFIG.water_shininess;
+
+		if g > GLINT_MIN {
+		    glint = self.atmosphere.sun_light()*
+			(g*self.visibility(coord, water_level, total_dist,
+					   self.sun_h_angle, self.sun_v_angle));
+		}
 	    }
 
 	    // Then, use Schlick's approximation to calculate reflection rate
 	    // of water.
-	    let r0 = 0.0200593121995248; // ((1.33 - 1)/(1.33 + 1))^2
-	    let r = r0 + (1.0 - r0)*(1.0 - (0.5*PI - r_angle).cos()).powi(5);
-
-	    color = seamix*r;
+	    color = seamix*water::fresnel(r_angle.sin()) + glint;
 	}
 	else {
 	    // Land. Determine rock or forest by height above sea and absolute
@@ -327,174 +817,758 @@ impl Renderer {
             shade:   cos(v) = g.s/(|g|*|s|)  [0 = shade, 1 = light]
 	     */
 	    let g = Coord3::new(-dhx, -dhy, 1.0);
-	    let light = ((g.dot(self.sun_ray))/g.abs()).max(0.0);
+	    let mut direct = BLACK;
 
-	    color = dark_color.blend(&land_color, light);
-	}
+	    let sun = ((g.dot(self.sun_ray))/g.abs()).max(0.0);
+	    if sun > 0.0 && self.sun_v_angle > 0.0 {
+		direct += self.atmosphere.sun_light()*
+		    (sun*self.visibility(coord, height, total_dist,
+					 self.sun_h_angle, self.sun_v_angle));
+	    }
+
+	    // Moonlight, calculated the same way as sunlight
+	    let moon = ((g.dot(self.moon_ray))/g.abs()).max(0.0);
+	    if moon > 0.0 && self.moon_v_angle > 0.0 {
+		direct += self.atmosphere.moon_light()*
+		    (moon*self.visibility(coord, height, total_dist,
+					  self.moon_h_angle, self.moon_v_angle));
+	    }
+
+	    // Terrain in shadow is only lit by ambient light from the sky
+	    let light = self.atmosphere.ambient_light() +
+		direct*(1.0 - CONFIG.ambient_light);
 
-	// Add blueness to distant terrain
-	let blued = LAND_BLUE.blend(&color, blueness);
+	    color = dark_color.blend_color(&land_color, light);
+	}
 
-	// Use haziness param to add whiteness to distant terrain
-        let whited = WHITE.blend(&blued, whiteness);
+	// Add scattering and extinction of light in the atmosphere, along the
+	// ray from where it starts, at the observer or at the water surface
+	// for reflected rays, to where it hits.
+	let view = direction_vector(h_angle, angle);
+	let end_height = water.unwrap_or(height);
 
-        return whited;
+	return self.atmosphere.apply(color, dist, view, start_height,
+				     end_height);
     }
 
-    fn sky_color(&self, angle: f32) -> Color {
+    // Fraction of a light source (the sun or the moon) which is visible
+    // from a point on the ground. A shadow ray is traced from the point
+    // towards the light source. For soft shadows, we trace a number of rays
+    // towards random points on the disc of the light source.
+    fn visibility(&mut self, coord: Coord, height: f32, total_dist: f32,
+		  light_h_angle: f32, light_v_angle: f32) -> f32 {
+	if !CONFIG.shadows {
+	    return 1.0;
+	}
+
+	let softness = CONFIG.shadow_softness.to_radians();
+	let mut n = 1;
+
+	if softness > 0.0 {
+	    n = CONFIG.shadow_samples.max(1);
+	}
+
+	let points = Stratified::new(&mut self.rng, n);
+	let mut lit = 0;
+
+	for i in 0..n {
+	    let mut h_angle = light_h_angle;
+	    let mut v_angle = light_v_angle;
+
+	    if softness > 0.0 {
+		// Uniformly distributed point on the disc, stratified by the
+		// distance from the centre
+		let [u, w] = points.point(&mut self.rng, i);
+		let a = w*2.0*PI;
+		let d = softness*u.sqrt();
+		h_angle += d*a.cos()/v_angle.cos();
+		v_angle += d*a.sin();
+	    }
+
+	    let ray_end = Coord::from_polar(CONFIG.max_depth, h_angle) + coord;
 
-	let sky_lum = 0.1*CONFIG.sky_lum;
+	    // Start the ray slightly above the ground and one map cell
+	    // away, so that it does not hit the terrain it starts from.
+	    // The shadow ray is traced in the same maps as the hit.
+	    let ray = self.render_ray(v_angle, total_dist, coord,
+				      height + SHADOW_RAY_OFFSET, ray_end,
+				      SHADOW_RAY_START);
+	    if ray.is_none() {
+		lit += 1;
+	    }
+	}
 
-	// 0 = light blue, 1 = dark blue
-	let lum = (sky_lum*(1.0 - 1.0/(angle + self.vertical_angle_corr).sin())).exp();
-	// 0 = white, 1 = blue
-	let haze = (0.01*CONFIG.haziness*(1.0 - 1.0/(angle + self.vertical_angle_corr).sin())).exp();
+	(lit as f32)/(n as f32)
+    }
 
-	// Blend light and dark blue, creating gradient of luminance towards
-	// the horizon.
-	let blended_blue = LIGHT_SKY_BLUE.blend(&DARK_SKY_BLUE, lum);
+    fn sky_color(&self, h_angle: f32, v_angle: f32) -> Color {
+	let view = direction_vector(h_angle, v_angle);
 
-	// Blend whiteness from haze.
-	let whited = WHITE.blend(&blended_blue, haze);
+	self.sky.color(view, v_angle + self.vertical_angle_corr, true)
+    }
+
+    // Sky color without the sun disc, for reflections on water, where the
+    // sun is added as glint.
+    fn sky_color_without_sun(&self, h_angle: f32, v_angle: f32) -> Color {
+	let view = direction_vector(h_angle, v_angle);
 
-	return whited;
+	self.sky.color(view, v_angle + self.vertical_angle_corr, false)
     }
 
     fn find_color(&mut self,
 		  ray_output: Option<(Coord, f32)>,
 		  passed_dist: f32,
+		  start_height: f32,
+		  h_angle: f32,
 		  v_angle: f32) -> Color {
 	if let Some((coord, r)) = ray_output {
 	    // Found land
             // Calculate straight distance (can be ommitted)
 	    //   r_straight = R_EARTH*(r/R_EARTH).sin()/(r/r_earth + v_angle).cos();
-	    return self.land_color(r, passed_dist + r, v_angle, coord);
+	    let hit = self.hit(coord, r, passed_dist + r);
+	    return self.land_color(&hit, passed_dist + r, start_height,
+				   h_angle, v_angle);
 	}
 	else {
 	    // Land was not found, assume sky
-	    return self.sky_color(v_angle);
+	    return self.sky_color(h_angle, v_angle);
 	}
     }
 
+    // Weight of the 1m maps at a distance from the observer. In the
+    // transition zone from r1 to r10, the weight falls smoothly from 1 to 0,
+    // so that there is no visible band where the resolution changes.
+    fn weight1(&self, total_dist: f32) -> f32 {
+	if total_dist <= self.r1 {
+	    return 1.0;
+	}
+	if total_dist >= self.r10 {
+	    return 0.0;
+	}
+
+	let t = (total_dist - self.r1)/(self.r10 - self.r1);
+	1.0 - t*t*(3.0 - 2.0*t)
+    }
+
+    // Terrain height at a distance from the observer. Close to the observer
+    // we use the 1m maps, far away the 10m maps, and in the transition zone
+    // a blend of both. The 1m maps are looked up before the render, see
+    // Detail. Where there are no 1m maps, we use the 10m maps.
+    fn height(&self, c: &Coord, total_dist: f32) -> Result<f32> {
+	let w = self.weight1(total_dist);
+
+	if w > 0.0 {
+	    if let Some(h1) = self.detail.as_ref().and_then(|d| d.lookup(c)) {
+		if w < 1.0 {
+		    if let Ok(h10) = self.atlas10.lookup(c) {
+			return Ok(w*h1 + (1.0 - w)*h10);
+		    }
+		}
+		return Ok(h1);
+	    }
+	}
+
+	self.atlas10.lookup(c)
+    }
+
+    // Terrain height and gradient, blended as in height()
+    fn terrain(&self, c: &Coord, total_dist: f32) -> Result<(f32, f32, f32)> {
+	let w = self.weight1(total_dist);
+
+	if w > 0.0 {
+	    let detail = self.detail.as_ref().and_then(|d| d.lookup_with_gradient(c));
+	    if let Some((h1, dx1, dy1)) = detail {
+		if w < 1.0 {
+		    if let Ok((h10, dx10, dy10)) = self.atlas10.lookup_with_gradient(c) {
+			return Ok((w*h1 + (1.0 - w)*h10,
+				   w*dx1 + (1.0 - w)*dx10,
+				   w*dy1 + (1.0 - w)*dy10));
+		    }
+		}
+		return Ok((h1, dx1, dy1));
+	    }
+	}
+
+	self.atlas10.lookup_with_gradient(c)
+    }
+
+    // Look up land height at coordinate, and check if a ray at height h
+    // is below it. Outside the maps, we assume sea level.
+    fn below_land(&self, c: &Coord, h: f32, total_dist: f32) -> bool {
+	if let Ok(land_height) = self.height(c, total_dist) {
+	    h < land_height
+	}
+	else {
+	    h < 0.0
+	}
+    }
+
+    // Find the largest distance the ray can skip from distance r, using
+    // the max-height pyramid. Starting at the top level, we check if the
+    // ray is above the maximum height of the cell all the way through the
+    // cell. If so, we skip to the border of the cell. If not, we try the
+    // level below. Returns the distance where the ray leaves the cell, or
+    // None if the ray cannot skip any cell.
+    fn pyramid_skip(pyramid: &Pyramid,
+		    v_angle: f32,
+		    observer: Coord,
+		    observer_height: f32,
+		    dir: Coord,
+		    r: f32) -> Option<f32> {
+	let c = dir*r + observer;
+
+	for level in (0..pyramid.levels()).rev() {
+	    let (max, bounds) = pyramid.cell(level, &c)?;
+
+	    // Distance from c to where the ray leaves the cell
+	    let mut exit = f32::MAX;
+	    if dir.e > 0.0 {
+		exit = exit.min((bounds[2] - c.e)/dir.e);
+	    }
+	    else if dir.e < 0.0 {
+		exit = exit.min((bounds[0] - c.e)/dir.e);
+	    }
+	    if dir.n > 0.0 {
+		exit = exit.min((bounds[3] - c.n)/dir.n);
+	    }
+	    else if dir.n < 0.0 {
+		exit = exit.min((bounds[1] - c.n)/dir.n);
+	    }
+
+	    if max < ray_min_height(r, r + exit, v_angle, observer_height) {
+		return Some(r + exit);
+	    }
+	}
+
+	None
+    }
+
     pub fn render_ray(&mut self,
 		      v_angle: f32,
 		      passed_dist: f32,
 		      observer: Coord,
 		      observer_height: f32,
-		      ray_end: Coord) -> Option<(Coord, f32)> {
-        // Iterate ray
-        let mut r = CONFIG.min_depth;
+		      ray_end: Coord,
+		      start_dist: f32) -> Option<(Coord, f32)> {
+	self.march(v_angle, passed_dist, observer, observer_height, ray_end,
+		   start_dist).map(|(c, r, _)| (c, r))
+    }
+
+    /*
+    Trace a ray from start_dist. The ray is sampled at steps of step(), each
+    from the end of the last. In the pyramid, each step is divided into
+    parts of at most PYRAMID_STEP, in order to not miss thin ridges, and the
+    samples where the ray is above the pyramid cells are skipped. Skipping
+    does not move the other samples, so with the pyramid, the ray never
+    finds land further away than without it. The hit is then refined
+    between the first sample below the terrain and the sample before it.
+
+    Returns the hit, its distance and the start of the step before the
+    first sample below the terrain. A ray above this one in the image
+    cannot hit land before that, and gets the same samples from there.
+     */
+    fn march(&mut self,
+	     v_angle: f32,
+	     passed_dist: f32,
+	     observer: Coord,
+	     observer_height: f32,
+	     ray_end: Coord,
+	     start_dist: f32) -> Option<(Coord, f32, f32)> {
+	let dir = (ray_end - observer)*(1.0/CONFIG.max_depth);
+	let pyramid = self.pyramid.clone();
+
+	// Start of this step and the step before, the last sample above
+	// land, and the distance up to which the ray is above the pyramid
+	let mut r = start_dist;
+	let mut r_last = start_dist;
+	let mut r_above = start_dist;
+	let mut skipped = f32::MIN;
 
+        // Iterate ray
         while r < CONFIG.max_depth {
-            // Calculate north and east coordinates
-            let c = (ray_end - observer)*(r/CONFIG.max_depth) + observer;
 	    let total_dist = passed_dist + r;
+	    let dr = self.step(total_dist);
+	    let mut parts = 1;
 
             // Calculate height
-	    let beta = r/R_EARTH;
-	    let alfa = beta + v_angle;
-	    let h = (R_EARTH + observer_height)*
-		(beta.cos() + beta.sin()*alfa.tan()) - R_EARTH;
+	    let h = ray_height(r, v_angle, observer_height);
 
             if h > 2600.0 {
                 // Above highest terrain level on Norwegian mainland
                 return None;
 	    }
 
-            let mut ret : Result<f32> = Err(
-                Error::Generic("Land not found".to_string()).into());
+	    // Skip empty space using the pyramid. The pyramid is built
+	    // from the 10m maps, so we only use it when we are far away.
+	    if let Some(p) = &pyramid {
+		if total_dist >= self.r10 {
+		    parts = (dr/PYRAMID_STEP).ceil() as u32;
+
+		    while skipped < r + dr {
+			let from = skipped.max(r);
+			match Renderer::pyramid_skip(p, v_angle, observer,
+						     observer_height, dir, from) {
+			    Some(to) if to > from => skipped = to,
+			    _ => break,
+			}
+		    }
+		}
+	    }
+
+	    for i in 0..parts {
+		let rs = r + dr*(i as f32)/(parts as f32);
+
+		if rs > skipped {
+		    // Calculate north and east coordinates
+		    let c = (ray_end - observer)*(rs/CONFIG.max_depth) + observer;
+		    let hs = if i == 0 { h } else { ray_height(rs, v_angle, observer_height) };
+
+		    if self.below_land(&c, hs, passed_dist + rs) {
+			// Found land
+			let restart = if i == 0 { r_last } else { r };
+
+			if pyramid.is_some() && rs > start_dist {
+			    let (c, rb) = self.refine_hit(v_angle, passed_dist, observer,
+							  observer_height, dir,
+							  (r_above, rs));
+			    return Some((c, rb, restart));
+			}
+
+			return Some((c, rs, restart));
+		    }
+		}
+
+		r_above = rs;
+	    }
+
+	    // Sky, step up ray distance, then continue
+	    r_last = r;
+	    r += dr;
+	}
+
+	None
+    }
+
+    // Step length of a ray at a distance from the observer, about the
+    // distance between neighbour samples on the ground
+    fn step(&self, total_dist: f32) -> f32 {
+	if total_dist < self.dr_min_range {
+	    self.dr_min
+	}
+	else if total_dist > self.dr_max_range {
+	    self.dr_max
+	}
+	else {
+	    total_dist/self.dr_factor
+	}
+    }
+
+    // Find the exact intersection between the ray and the terrain by
+    // bisection between the last sample above and the first sample below
+    // the terrain, given as the distances (r_above, r_below).
+    fn refine_hit(&self,
+		  v_angle: f32,
+		  passed_dist: f32,
+		  observer: Coord,
+		  observer_height: f32,
+		  dir: Coord,
+		  (r_above, r_below): (f32, f32)) -> (Coord, f32) {
+	let mut ra = r_above;
+	let mut rb = r_below;
+
+	for _ in 0..8 {
+	    let r = 0.5*(ra + rb);
+	    let c = dir*r + observer;
+	    let h = ray_height(r, v_angle, observer_height);
+
+	    if self.below_land(&c, h, passed_dist + r) {
+		rb = r;
+	    }
+	    else {
+		ra = r;
+	    }
+	}
+
+	(dir*rb + observer, rb)
+    }
+
+    // Color of a traced sample. The random numbers used for shading the
+    // sample are given by the eye, the pixel and the index of the sample in
+    // the pixel, so that it is shaded the same way every time.
+    fn shade_sample(&mut self, sample: &Sample, x: u32, y: u32, index: usize)
+		    -> Color {
+	self.rng = random::generator(&[self.eye.code() as u64, x as u64,
+				       y as u64, index as u64]);
+
+	match &sample.hit {
+	    Some(hit) => self.land_color(hit, hit.dist, self.observer_height,
+					 sample.h_angle, sample.v_angle),
+	    None => self.sky_color(sample.h_angle, sample.v_angle),
+	}
+    }
+
+    /*
+    Trace column i of the samples of pixel column x, from the bottom line
+    and up, with sampling.ny samples per line. A ray cannot hit land
+    closer to the observer than the ray below it in the same column, since
+    it is above that ray all the way. Each ray therefore starts at the step
+    where the ray below hit land, see march(). The steps of a ray only
+    depend on the distance, so from there, the ray gets the same samples as
+    when it is traced from min_depth, and finds the same hit. When a ray
+    hits the sky, all rays above it in the column will also hit the sky.
+    This only holds when the whole column has the same directional angle.
+    Otherwise, e.g. in the up and down faces of a cubemap, each ray is
+    traced from min_depth.
+
+    Returns the sum of the sample colors for each line, the hit distance
+    of the lowest sample in the line (None for sky), and whether all
+    samples of the line are masked. The traced samples are added to the
+    hits of each line.
+     */
+    fn trace_column(&mut self, x: u32, i: u32, sampling: &Sampling,
+		    hits: &mut [Hits]) -> Vec<(Color, Option<f32>, bool)> {
+	let fx = (x as f32) + Sampling::centre_offset(i, sampling.nx);
+	let coherent = self.projection.coherent(fx);
+	let mut res = vec![(BLACK, None, true); CONFIG.height as usize];
+
+	// Start of the step where the last ray in this column hit land. None
+	// when we have reached the sky.
+	let mut last_hit = Some(CONFIG.min_depth);
+
+	for y in (0..CONFIG.height).rev() {
+	    let (sum, dist, masked) = &mut res[y as usize];
+	    let key = random::hash(&[x as u64, y as u64, (i + 1) as u64]);
+
+	    // Sample offsets increase downwards in the image
+	    for j in (0..sampling.ny).rev() {
+		let fy = (y as f32) + sampling.offset(j, sampling.ny, key);
+		if self.projection.masked(fx, fy) {
+		    continue;
+		}
+		*masked = false;
+
+		let (h_angle, v_angle) = self.projection.angles(fx, fy);
+		let ray_end = Coord::from_polar(CONFIG.max_depth, h_angle) + self.observer;
+		let mut ray = None;
 
-	    if total_dist < self.r10 {
-		// Try 1m resolution lookup if we are close
-		ret = self.atlas1.lookup(&c);
+		if !coherent {
+		    last_hit = Some(CONFIG.min_depth);
+		}
+
+		if let Some(start) = last_hit {
+		    let hit = self.march(v_angle, 0.0, self.observer,
+					 self.observer_height, ray_end, start);
+		    ray = hit.map(|(c, r, _)| (c, r));
+		    last_hit = hit.map(|(_, _, restart)| restart);
+		}
+
+		if j == sampling.ny - 1 {
+		    *dist = ray.map(|(_, r)| r);
+		}
+
+		let sample = Sample {
+		    h_angle,
+		    v_angle,
+		    hit: ray.map(|(c, r)| self.hit(c, r, r)),
+		};
+		let samples = &mut hits[y as usize].samples;
+		let index = samples.len();
+		samples.push(sample);
+		*sum += self.shade_sample(&sample, x, y, index);
 	    }
+	}
 
-	    if let Err(_) = ret {
-		// Fallback to 10m resolution
-		ret = self.atlas10.lookup(&c);
+	res
+    }
+
+    // Trace all samples of a single pixel, each ray from min_depth
+    fn trace_pixel(&mut self, x: u32, y: u32, sampling: &Sampling,
+		   hits: &mut Hits) -> Color {
+	let mut sum = BLACK;
+
+	hits.scale = 1.0/(sampling.count() as f32);
+	hits.samples.clear();
+
+	for i in 0..sampling.nx {
+	    let fx = (x as f32) + sampling.offset(
+		i, sampling.nx, random::hash(&[x as u64, y as u64]));
+	    let key = random::hash(&[x as u64, y as u64, (i + 1) as u64]);
+
+	    for j in 0..sampling.ny {
+		let fy = (y as f32) + sampling.offset(j, sampling.ny, key);
+		if self.projection.masked(fx, fy) {
+		    continue;
+		}
+
+		let (h_angle, v_angle) = self.projection.angles(fx, fy);
+		let ray_end = Coord::from_polar(CONFIG.max_depth, h_angle) + self.observer;
+		let ray = self.render_ray(v_angle, 0.0, self.observer,
+					  self.observer_height, ray_end,
+					  CONFIG.min_depth);
+
+		let sample = Sample {
+		    h_angle,
+		    v_angle,
+		    hit: ray.map(|(c, r)| self.hit(c, r, r)),
+		};
+		hits.samples.push(sample);
+		sum += self.shade_sample(&sample, x, y, hits.samples.len() - 1);
 	    }
-	    
-	    if let Ok(land_height) = ret {
-                if h < land_height {
-                    // Found land. If we are close, check if the 1m maps are
-		    // loaded. If not, load them, go back 50m and continue
-		    // tracing
-		    if total_dist < self.r10 {
-			if self.atlas1.has_maps(&c) &&
-			    !self.atlas1.has_images(&c) {
-			    let _ = self.atlas1.load_images(&c);
-			    r -= 50.0;
-			    continue;
+	}
+
+	sum*hits.scale
+    }
+
+    // Check if a pixel differs from any of its neighbours by more than the
+    // adaptive thresholds, in color or in depth. Land next to sky is always
+    // an edge. Pixels outside the fisheye mask are black, and are neither
+    // edges nor compared with.
+    fn is_edge(base: &[Vec<(Color, Option<f32>, bool)>], i: usize, y: usize)
+	       -> bool {
+	let (color, dist, masked) = base[i][y];
+	if masked {
+	    return false;
+	}
+
+	for column in &base[i.saturating_sub(1)..(i + 2).min(base.len())] {
+	    for &(n_color, n_dist, n_masked) in
+		&column[y.saturating_sub(1)..(y + 2).min(column.len())] {
+		if n_masked {
+		    continue;
+		}
+
+		if color.difference(&n_color) > CONFIG.adaptive_color {
+		    return true;
+		}
+
+		match (dist, n_dist) {
+		    (Some(d0), Some(d1)) => {
+			if (d0 - d1).abs() > CONFIG.adaptive_depth*d0.min(d1) {
+			    return true;
 			}
+		    },
+		    (None, None) => {},
+		    _ => return true,
+		}
+	    }
+	}
+
+	false
+    }
+
+    /*
+    Trace a vertical strip of the image. Each pixel is the average of a grid
+    of samples. With stratified sampling, the samples in a column of the
+    grid have the same horizontal offset all the way up the image, so that
+    we can trace them bottom-up as a column. Jittered samples have a random
+    horizontal offset in each pixel, and are traced pixel by pixel.
+
+    In adaptive mode, we first trace one sample per pixel, including the
+    columns on each side of the strip. Only pixels which differ much from a
+    neighbour, typically at ridgelines and depth discontinuities, are
+    supersampled.
+
+    Returns the traced samples and the color of each pixel, column by
+    column.
+     */
+    fn trace_strip(&mut self, x_start: u32, x_end: u32) -> (Vec<Hits>, Vec<Color>) {
+	let height = CONFIG.height as usize;
+	let sampling = self.sampling;
+	let n = ((x_end - x_start) as usize)*height;
+	let mut pixels = vec![BLACK; n];
+	let mut hits;
+
+	if CONFIG.adaptive {
+	    let xa = x_start.saturating_sub(1);
+	    let xb = (x_end + 1).min(CONFIG.width);
+	    let single = Sampling::single();
+	    let mut base = Vec::new();
+	    let mut base_hits = Vec::new();
+
+	    for x in xa..xb {
+		let mut column_hits = vec![Hits { scale: 1.0, samples: Vec::new() }; height];
+		base.push(self.trace_column(x, 0, &single, &mut column_hits));
+		base_hits.push(column_hits);
+	    }
+
+	    hits = Vec::with_capacity(n);
+	    for x in x_start..x_end {
+		let i = (x - xa) as usize;
+		for y in 0..height {
+		    let mut h = std::mem::take(&mut base_hits[i][y]);
+		    let p = &mut pixels[((x - x_start) as usize)*height + y];
+
+		    if sampling.count() > 1 && Renderer::is_edge(&base, i, y) {
+			*p = self.trace_pixel(x, y as u32, &sampling, &mut h);
+		    }
+		    else {
+			*p = base[i][y].0;
 		    }
 
-		    return Some((c, r));
+		    hits.push(h);
 		}
 	    }
-	    else {
-		// Left map. Assume sea level.
-		if h < 0.0 {
-		    return Some((c, r));
+	}
+	else if sampling.jittered() {
+	    hits = vec![Hits::default(); n];
+
+	    for x in x_start..x_end {
+		let p = ((x - x_start) as usize)*height;
+
+		for y in 0..height {
+		    pixels[p + y] = self.trace_pixel(x, y as u32, &sampling,
+						     &mut hits[p + y]);
 		}
 	    }
+	}
+	else {
+	    let scale = 1.0/(sampling.count() as f32);
+	    hits = vec![Hits { scale, samples: Vec::new() }; n];
 
-	    // Sky, step up ray distance, then continue
-	    if total_dist < self.dr_min_range {
-		r += self.dr_min;
+	    for x in x_start..x_end {
+		let p = ((x - x_start) as usize)*height;
+
+		for i in 0..sampling.nx {
+		    let column = self.trace_column(x, i, &sampling,
+						   &mut hits[p..p + height]);
+
+		    for (y, (sum, _, _)) in column.into_iter().enumerate() {
+			pixels[p + y] += sum*scale;
+		    }
+		}
 	    }
-	    else if total_dist > self.dr_max_range {
-		r += self.dr_max;
+	}
+
+	(hits, pixels)
+    }
+
+    // Shade the traced samples of a strip, with the light of the current
+    // view.
+    fn shade_strip(&mut self, x_start: u32, hits: &[Hits]) -> Vec<Color> {
+	let height = CONFIG.height as usize;
+	let mut pixels = Vec::with_capacity(hits.len());
+
+	for (p, h) in hits.iter().enumerate() {
+	    let x = x_start + (p/height) as u32;
+	    let y = (p % height) as u32;
+	    let mut sum = BLACK;
+
+	    for (index, sample) in h.samples.iter().enumerate() {
+		sum += self.shade_sample(sample, x, y, index);
 	    }
-	    else {
-		r += total_dist/self.dr_factor;
+	    pixels.push(sum*h.scale);
+	}
+
+	pixels
+    }
+
+    // The terrain hit by a view ray, with the position computed in double
+    // precision from the observer and the distance along the ray
+    fn surface(&self, sample: &Sample) -> Surface {
+	let hit = sample.hit.unwrap();
+	let (h, d) = (sample.h_angle as f64, hit.dist as f64);
+
+	Surface {
+	    hit,
+	    e: self.observer.e as f64 + d*h.cos(),
+	    n: self.observer.n as f64 + d*h.sin(),
+	}
+    }
+
+    // Send the pixels of a finished strip to the output thread, and the
+    // terrain seen in each pixel if there are rasters in config.
+    fn send_strip(&self, x_start: u32, x_end: u32, pixels: &[Color],
+		  hits: &[Hits]) {
+	let height = CONFIG.height as usize;
+	let rasters = !CONFIG.rasters.is_empty();
+
+	if let Some(tx) = &self.ptx {
+	    for x in x_start..x_end {
+		for y in 0..height {
+		    let i = ((x - x_start) as usize)*height + y;
+		    tx.send(RenderOutput::DrawPixel(self.eye, x, y as u32, pixels[i])).unwrap();
+
+		    if rasters {
+			let surface = hits[i].surface().map(|s| self.surface(s));
+			tx.send(RenderOutput::DrawHit(self.eye, x, y as u32,
+						      surface)).unwrap();
+		    }
+		}
 	    }
+
+	    tx.send(RenderOutput::IncProgress((x_end - x_start).into())).unwrap();
 	}
+    }
 
-	None
+    // Render a vertical strip of the image
+    pub fn render_columns(&mut self, x_start: u32, x_end: u32) {
+	let (hits, pixels) = self.trace_strip(x_start, x_end);
+	self.send_strip(x_start, x_end, &pixels, &hits);
     }
 
-    pub fn render_all(&mut self) {
-        let o = CONFIG.observer;
+    // Render a strip, reusing the samples traced for another view with the
+    // same camera, so that only the shading is done again. Without samples
+    // for all pixels of the strip, the strip is traced, and the samples are
+    // kept in hits.
+    fn render_columns_cached(&mut self, x_start: u32, x_end: u32,
+			     hits: &mut Option<Vec<Hits>>) {
+	let n = ((x_end - x_start)*CONFIG.height) as usize;
+
+	let pixels = match hits {
+	    Some(h) if h.len() == n => self.shade_strip(x_start, h),
+	    _ => {
+		let (h, pixels) = self.trace_strip(x_start, x_end);
+		*hits = Some(h);
+		pixels
+	    },
+	};
+
+	self.send_strip(x_start, x_end, &pixels, hits.as_deref().unwrap_or(&[]));
+    }
 
-        for y in 0..CONFIG.height {
-            // Calculate vertical angle
-            let v_angle: f32 = self.vertical_middle_angle +
-		(((CONFIG.height as f32)/2.0 - (y as f32))/self.focus_depth).atan();
-            //            println!("Line {}", y);
-
-            for x in 0..CONFIG.width {
-		// Calculate directional angle
-                let h_angle = self.horizontal_middle_angle +
-		    (((CONFIG.width as f32)/2.0 - (x as f32))/self.focus_depth).atan();
-                // Calculate ray endpoint
-                let ray_end = Coord::from_polar(CONFIG.max_depth, h_angle) + o;
-
-		let ray = self.render_ray(v_angle, 0.0, CONFIG.observer, self.observer_height, ray_end);
-		let color = self.find_color(ray, 0.0, v_angle);
-
-                if let Some(tx) = &self.ptx {
-                    tx.send(RenderOutput::DrawPixel(x, y, color)).unwrap();
-                }
-            }
+    pub fn render_all(&mut self) {
+	self.render_columns(0, CONFIG.width);
+    }
 
-            if let Some(tx) = &self.ptx {
-	        tx.send(RenderOutput::IncProgress(1)).unwrap();
-            }
+    /*
+    Trace a ray through the middle of each pixel of a strip from min_depth,
+    column by column, without shading. Returns where each ray hits the
+    terrain, its distance and the step length of the ray there, for
+    comparing the hits of different ways of tracing. None where a ray hits
+    the sky.
+     */
+    pub fn trace_rays(&mut self, x_start: u32, x_end: u32)
+		      -> Vec<Option<(Coord, f32, f32)>> {
+	let mut res = Vec::new();
+
+	for x in x_start..x_end {
+	    for y in 0..CONFIG.height {
+		let (h_angle, v_angle) = self.projection.angles(x as f32, y as f32);
+		let ray_end = Coord::from_polar(CONFIG.max_depth, h_angle) + self.observer;
+		let ray = self.render_ray(v_angle, 0.0, self.observer,
+					  self.observer_height, ray_end,
+					  CONFIG.min_depth);
+		res.push(ray.map(|(c, r)| (c, r, self.step(r))));
+	    }
 	}
+
+	res
     }
 
     pub fn find_horizon(&mut self) -> Result<Coord> {
-        let o = CONFIG.observer;
+        let o = self.observer;
 
         for y in 0..CONFIG.height {
             // Calculate vertical angle
-            let v_angle: f32 = self.vertical_middle_angle +
-		(((CONFIG.height as f32)/2.0 - (y as f32))/self.focus_depth).atan();
+            let (_, v_angle) = self.projection.angles(
+		0.5*(CONFIG.width as f32) - 0.5, y as f32);
             // Calculate ray endpoint
             let ray_end = Coord::from_polar(CONFIG.max_depth,
-					    self.horizontal_middle_angle) + o;
+					    self.projection.h_middle()) + o;
 
-	    let ray = self.render_ray(v_angle, 0.0, CONFIG.observer, self.observer_height, ray_end);
+	    let ray = self.render_ray(v_angle, 0.0, self.observer,
+				      self.observer_height, ray_end,
+				      CONFIG.min_depth);
 
 	    if let Some((coord, _)) = ray {
 		return Ok(coord);
@@ -503,22 +1577,595 @@ impl Renderer {
 	Err(Error::Generic("Could not find horizon".to_string()).into())
     }
 
+    // Number of render worker threads. 0 in config means one thread per
+    // available cpu.
+    fn num_threads() -> usize {
+	if CONFIG.threads > 0 {
+	    return CONFIG.threads as usize;
+	}
+
+	available_parallelism().map(|n| n.get()).unwrap_or(1)
+    }
+
+    /*
+    Render thread. Each worker owns its own 10m maps and a renderer for the
+    current frame, and renders strips from the queue until it is closed.
+    When a strip belongs to a new frame, the worker creates a renderer for
+    the view of that frame, passing on the maps. This way, the maps loaded
+    for one frame are kept for the next. The 1m maps, the pyramid and the
+    lakes come with the strip, and are the same for all workers. Each
+    finished strip is reported on dtx. The first worker reports the camera
+    orientation of the first frame. With a hit cache, the traced samples of
+    each strip are kept, and reused for the next frames.
+     */
+    fn render_worker(strips: Receiver<Strip>, shared: Shared, report: bool)
+		     -> Result<()> {
+	let Shared { views, water_mask, bathymetry, cache, ptx, mtx, dtx } = shared;
+	let mut atlas10: Option<Box<dyn Heights>> =
+	    Some(Box::new(Atlas::new(10.0, Some(mtx.clone()))?));
+	let mut current: Option<(usize, Renderer)> = None;
+
+	while let Ok(Strip { frame, eye, x, terrain }) = strips.recv() {
+	    if current.as_ref().map(|(f, _)| *f) != Some(frame) {
+		let atlas10 = match current.take() {
+		    Some((_, r)) => r.into_atlas(),
+		    None => atlas10.take().unwrap(),
+		};
+
+		let mut r = Renderer::with_view(atlas10, Some(ptx.clone()),
+						&views[frame])?;
+
+		if report && frame == 0 {
+		    let (azimuth, pitch, roll) = r.orientation();
+		    mtx.send(format!("Camera azimuth {:.3}, pitch {:.3}, roll {:.3}",
+				     azimuth, pitch, roll)).unwrap();
+		}
+
+		if let Some(d) = &terrain.detail {
+		    r.set_detail(d.clone());
+		}
+
+		if let Some(p) = &terrain.pyramid {
+		    r.set_pyramid(p.clone());
+		}
+
+		if let Some(l) = &terrain.lakes {
+		    r.set_lakes(l.clone());
+		}
+
+		if let Some(m) = &water_mask {
+		    r.set_water_mask(m.clone());
+		}
+
+		if let Some(b) = &bathymetry {
+		    r.set_bathymetry(b.clone());
+		}
+
+		current = Some((frame, r));
+	    }
+
+	    if let Some((_, r)) = current.as_mut() {
+		let x_end = (x + STRIP_WIDTH).min(CONFIG.width);
+		r.set_eye(eye)?;
+
+		match &cache {
+		    Some(c) => {
+			let mut hits = c.lock().unwrap().remove(&(eye, x));
+			r.render_columns_cached(x, x_end, &mut hits);
+			if let Some(h) = hits {
+			    c.lock().unwrap().insert((eye, x), h);
+			}
+		    },
+		    None => r.render_columns(x, x_end),
+		}
+	    }
+
+	    dtx.send(Ok(())).unwrap();
+	}
+
+	Ok(())
+    }
+
+    /*
+    Render the image, or all frames of an animation. With gbuffer in config,
+    the geometry buffer of the image is saved, for relighting it later.
+     */
     pub fn render() -> Result<()> {
+	let frames = animation::frames()?;
+	let animated = frames.is_some();
+	let views = frames.unwrap_or_else(|| vec![View::from_config()]);
+	let save_gbuffer = !animated && !CONFIG.gbuffer.is_empty();
+
+	// The frames of a time-lapse have the same camera, so the rays are
+	// traced for the first frame only. The next frames are shaded from
+	// the hits of the first.
+	let mut cache = None;
+	if save_gbuffer ||
+	    (views.len() > 1 && views.windows(2).all(|w| w[0].same_camera(&w[1]))) {
+	    cache = Some(HashMap::new());
+	}
+
+	let view = views[0].clone();
+	let strips = Renderer::render_views(views, animated, cache)?;
+
+	if let (true, Some(strips)) = (save_gbuffer, strips) {
+	    let gbuffer = GBuffer {
+		width: CONFIG.width,
+		height: CONFIG.height,
+		view,
+		strips,
+	    };
+	    gbuffer.save(&CONFIG.gbuffer)?;
+	    println!("Saved geometry buffer to {}", CONFIG.gbuffer);
+	}
+
+	Ok(())
+    }
+
+    /*
+    Shade the image again from the geometry buffer saved by an earlier
+    render, with the time, haziness, snow limit etc. from config. The view
+    rays are not traced, only the shadow rays and the reflections on water.
+    The rest of the config should be as for the saved render.
+     */
+    pub fn relight() -> Result<()> {
+	let gbuffer = GBuffer::load(&CONFIG.gbuffer)?;
+
+	if gbuffer.width != CONFIG.width || gbuffer.height != CONFIG.height {
+	    return Err(Error::Generic(format!(
+		"Image size {}x{} differs from the geometry buffer, {}x{}",
+		CONFIG.width, CONFIG.height, gbuffer.width, gbuffer.height)));
+	}
+
+	let mut view = gbuffer.view;
+	view.time = CONFIG.time.clone();
+
+	Renderer::render_views(vec![view], false, Some(gbuffer.strips))?;
+
+	Ok(())
+    }
+
+    // Check the names of options in config, so that an unknown name is
+    // reported before the render and output threads are started.
+    fn check_config() -> Result<()> {
+	ToneMap::from(&CONFIG.tonemap)?;
+	Sampling::from_config()?;
+	Kind::from_config()?;
+	Stereo::from_config()?;
+	animation::Format::from_config()?;
+	Layer::from_config()?;
+	WaterType::from(&CONFIG.water_type)?;
+	WaterType::from(&CONFIG.lake_type)?;
+
+	Ok(())
+    }
+
+    // Check if the terrain data covers the view of a frame
+    fn covers(atlas10: &dyn Heights, terrain: &Terrain, view: &View)
+	      -> Result<bool> {
+	let (c_min, c_max) = match (terrain.c_min, terrain.c_max) {
+	    (Some(c_min), Some(c_max)) => (c_min, c_max),
+	    _ => return Ok(false),
+	};
+	let (v_min, v_max) = Renderer::view_bounds(atlas10, view)?;
+	let blocks = Renderer::detail_blocks(atlas10, view)?;
+
+	Ok(v_min.e >= c_min.e && v_min.n >= c_min.n &&
+	   v_max.e <= c_max.e && v_max.n <= c_max.n &&
+	   terrain.detail.as_ref().is_some_and(|d| d.covers(&blocks)))
+    }
+
+    /*
+    Make the terrain data for the views of some frames. The blocks of the
+    1m maps which are in the previous terrain data are reused, since the
+    views of the next frames are usually close. The time it takes is
+    reported, since it is also needed for a relight.
+     */
+    fn build_terrain(atlas10: &dyn Heights, views: &[View], previous: &Terrain,
+		     water_mask: &Option<Arc<WaterMask>>, mtx: &MsgSender)
+		     -> Result<Terrain> {
+	let threads = Renderer::num_threads();
+	let start = Instant::now();
+
+	// Rectangle covering the views
+	let (mut c_min, mut c_max) = Renderer::view_bounds(atlas10, &views[0])?;
+	let mut blocks = Vec::new();
+	for view in views {
+	    let (v_min, v_max) = Renderer::view_bounds(atlas10, view)?;
+	    c_min.e = c_min.e.min(v_min.e);
+	    c_min.n = c_min.n.min(v_min.n);
+	    c_max.e = c_max.e.max(v_max.e);
+	    c_max.n = c_max.n.max(v_max.n);
+	    blocks.extend(Renderer::detail_blocks(atlas10, view)?);
+	}
+	blocks.sort();
+	blocks.dedup();
+
+	mtx.send("Loading 1m maps".to_string()).unwrap();
+	let detail = Detail::build(&blocks, previous.detail.as_deref(), threads)?;
+
+	// Build max-height pyramid covering the views
+	let mut pyramid = None;
+	if CONFIG.pyramid {
+	    mtx.send("Building height pyramid".to_string()).unwrap();
+	    pyramid = Some(Arc::new(Pyramid::build(c_min, c_max, threads)?));
+	}
+
+	// Find the lakes in the views
+	let mut lakes = None;
+	if water_mask.is_some() || CONFIG.lake_detection ||
+	    CONFIG.shore_distance > 0.0 {
+	    mtx.send("Finding lakes".to_string()).unwrap();
+	    lakes = Some(Arc::new(Lakes::build(c_min, c_max, water_mask.clone(),
+					       threads)?));
+	}
+
+	mtx.send(format!("Terrain data: {:.2?}", start.elapsed())).unwrap();
+
+	Ok(Terrain {
+	    c_min: Some(c_min),
+	    c_max: Some(c_max),
+	    detail: Some(Arc::new(detail)),
+	    pyramid,
+	    lakes,
+	})
+    }
+
+    /*
+    Render the views of all frames. The strips of each frame are queued for
+    the workers, and we wait until they are all finished before the frame
+    is saved and the next frame is queued. The terrain data is made for the
+    next TERRAIN_FRAMES frames when it does not cover the view of a frame,
+    so that a long camera path is not covered all at once. With a hit
+    cache, the traced samples are reused between the frames, and returned
+    at the end.
+     */
+    fn render_views(views: Vec<View>, animated: bool, cache: Option<Strips>)
+		    -> Result<Option<Strips>> {
+	Renderer::check_config()?;
+
+	let eyes = Stereo::from_config()?.eyes();
+	let views = Arc::new(views);
+	let cache = cache.map(|c| Arc::new(Mutex::new(c)));
+
         // Create communication channels
         let (ptx, prx): (ProgressSender, ProgressReceiver) = unbounded();
         let (mtx, mrx): (MsgSender, MsgReceiver) = unbounded();
-        let output = spawn(move || handle_output(prx, mrx));
+	let n_frames = views.len();
+        let output = spawn(move || handle_output(prx, mrx, n_frames, animated));
+
+	let mut water_mask = None;
+	if !CONFIG.water_mask.is_empty() {
+	    water_mask = Some(Arc::new(WaterMask::load(&CONFIG.water_mask)?));
+	}
+
+	let mut bathymetry = None;
+	if !CONFIG.bathymetry.is_empty() {
+	    bathymetry = Some(Arc::new(Bathymetry::load(&CONFIG.bathymetry)?));
+	}
 
-        let atlas1 = Atlas::new(1.0, Some(mtx.clone()))?;
-        let atlas10 = Atlas::new(10.0, Some(mtx))?;
+	let (stx, srx) = unbounded::<Strip>();
+	let (dtx, drx) = unbounded();
+	let shared = Shared {
+	    views: views.clone(),
+	    water_mask: water_mask.clone(),
+	    bathymetry,
+	    cache: cache.clone(),
+	    ptx: ptx.clone(),
+	    mtx: mtx.clone(),
+	    dtx,
+	};
+
+	let mut workers = Vec::new();
+	for t in 0..Renderer::num_threads() {
+	    let srx = srx.clone();
+	    let shared = shared.clone();
+
+	    workers.push(spawn(move || {
+		let dtx = shared.dtx.clone();
+		let res = Renderer::render_worker(srx, shared, t == 0)
+		    .map_err(|e| e.to_string());
+		if let Err(e) = &res {
+		    let _ = dtx.send(Err(e.clone()));
+		}
+		res
+	    }));
+	}
+	drop(shared);
+
+	// The 10m maps for finding the terrain in view, with the pitch of the
+	// views
+	let atlas10 = Atlas::new(10.0, Some(mtx.clone()))?;
+	let mut res = Ok(());
+	let mut terrain = Arc::new(Terrain::default());
+
+	'frames: for frame in 0..views.len() {
+	    match Renderer::covers(&atlas10, &terrain, &views[frame]) {
+		Ok(true) => {},
+		Ok(false) => {
+		    let end = (frame + TERRAIN_FRAMES).min(views.len());
+		    match Renderer::build_terrain(&atlas10, &views[frame..end],
+						  &terrain, &water_mask, &mtx) {
+			Ok(t) => terrain = Arc::new(t),
+			Err(e) => {
+			    res = Err(e);
+			    break 'frames;
+			},
+		    }
+		},
+		Err(e) => {
+		    res = Err(e);
+		    break 'frames;
+		},
+	    }
+
+	    // Queue all strips of the frame, for each eye
+	    let mut n = 0;
+	    for eye in &eyes {
+		for x in (0..CONFIG.width).step_by(STRIP_WIDTH as usize) {
+		    stx.send(Strip {
+			frame,
+			eye: *eye,
+			x,
+			terrain: terrain.clone(),
+		    }).unwrap();
+		    n += 1;
+		}
+	    }
 
-        let mut r = Renderer::new(atlas1, atlas10, Some(ptx.clone())).unwrap();
-        r.render_all();
+	    for _ in 0..n {
+		match drx.recv() {
+		    Ok(Ok(())) => {},
+		    Ok(Err(e)) => {
+			res = Err(Error::Generic(e));
+			break 'frames;
+		    },
+		    Err(_) => break 'frames,
+		}
+	    }
+
+	    if animated {
+		ptx.send(RenderOutput::SaveFrame(frame)).unwrap();
+	    }
+	}
+	drop(stx);
+
+	for w in workers {
+	    if let Err(e) = w.join().unwrap() {
+		res = Err(Error::Generic(e));
+	    }
+	}
 
         ptx.send(RenderOutput::Finish).unwrap();
 
-        output.join().unwrap();
+        output.join().unwrap()?;
+
+	res?;
+
+	// The workers are done with the cache
+	Ok(cache.and_then(|c| Arc::try_unwrap(c).ok())
+	   .map(|c| c.into_inner().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::Pattern;
+
+    // Columns of the image rendered in the tests
+    const X0: u32 = 796;
+    const X1: u32 = 804;
+
+    fn coord(e: f32, n: f32) -> Coord {
+	let mut c = Coord::from_polar(0.0, 0.0);
+	c.e = e;
+	c.n = n;
+	c
+    }
+
+    fn observer() -> Coord {
+	coord(100125.0, 6900125.0)
+    }
+
+    // Terrain for the tests: a bowl around the observer with hills, where
+    // the bottom of the bowl is partly below water_level.
+    struct Hills;
+
+    impl Hills {
+	fn height(c: &Coord) -> f32 {
+	    let d = (*c - observer()).abs();
+	    0.12*(d - 800.0).max(0.0) + 20.0*(c.e/170.0).sin()*(c.n/230.0).cos() - 5.0
+	}
+    }
+
+    impl Heights for Hills {
+	fn lookup(&self, c: &Coord) -> Result<f32> {
+	    Ok(Hills::height(c))
+	}
+
+	fn lookup_with_gradient(&self, c: &Coord) -> Result<(f32, f32, f32)> {
+	    let (dx, dy) = (coord(1.0, 0.0), coord(0.0, 1.0));
+	    Ok((Hills::height(c),
+		0.5*(Hills::height(&(*c + dx)) - Hills::height(&(*c - dx))),
+		0.5*(Hills::height(&(*c + dy)) - Hills::height(&(*c - dy)))))
+	}
+    }
+
+    // Heights of the 1m maps near the observer, with more detail
+    fn detail() -> Arc<Detail> {
+	let keys = Detail::blocks(observer(), 0.0, PI, 600.0, 0.0);
+	Arc::new(Detail::from_fn(&keys, |c| {
+	    Hills::height(c) + 0.5*(c.e/7.0).sin()*(c.n/11.0).sin()
+	}))
+    }
+
+    // Looking east towards a target at a distance, down at the bottom of
+    // the bowl from a close target. The transition from the 1m to the 10m
+    // maps is close, so that the detail is small.
+    fn renderer(target: f32, detail: Option<Arc<Detail>>) -> Renderer {
+	let view = View {
+	    observer: observer(),
+	    target: observer() + coord(target, 0.0),
+	    observer_height: Some(60.0),
+	    time: CONFIG.time.clone(),
+	    width_angle: CONFIG.width_angle,
+	};
+
+	let mut r = Renderer::with_view(Box::new(Hills), None, &view).unwrap();
+	r.r1 = 300.0;
+	r.r10 = 600.0;
+	if let Some(d) = detail {
+	    r.set_detail(d);
+	}
+
+	r
+    }
+
+    // Pixels of the columns X0 to X1, rendered by a number of threads in
+    // strips of two columns. Each thread has its own renderer, and starts
+    // at another strip.
+    fn render(threads: u32, detail: &Arc<Detail>) -> Vec<[f32; 3]> {
+	let mut workers = Vec::new();
+
+	for t in 0..threads {
+	    let detail = detail.clone();
+	    workers.push(spawn(move || {
+		let mut r = renderer(3000.0, Some(detail));
+		let mut strips = Vec::new();
+		for x in (X0 + 2*t..X1).step_by(2*(threads as usize)) {
+		    strips.push((x, r.trace_strip(x, x + 2).1));
+		}
+		strips
+	    }));
+	}
+
+	let mut strips: Vec<(u32, Vec<Color>)> = workers.into_iter()
+	    .flat_map(|w| w.join().unwrap())
+	    .collect();
+	strips.sort_by_key(|(x, _)| *x);
+
+	strips.into_iter()
+	    .flat_map(|(_, pixels)| pixels.into_iter().map(|p| p.as_array()))
+	    .collect()
+    }
+
+    #[test]
+    fn threads_render_same_image() {
+	let detail = detail();
+	let one = render(1, &detail);
+
+	assert_eq!(one.len(), ((X1 - X0)*CONFIG.height) as usize);
+	assert!(one == render(4, &detail));
+    }
+
+    #[test]
+    fn jittered_samples_vary_horizontally() {
+	let mut r = renderer(3000.0, Some(detail()));
+	r.sampling = Sampling::new(4, Pattern::Jittered);
+	let (hits, _) = r.trace_strip(X0, X0 + 1);
+
+	// Offset of the first sample of each pixel from the left edge of the
+	// pixel column, in the range 0 to 0.5 for the left half
+	let left = r.projection.angles(X0 as f32 - 0.5, 100.0).0;
+	let right = r.projection.angles(X0 as f32 + 0.5, 100.0).0;
+	let offsets: Vec<f32> = hits.iter()
+	    .map(|h| (h.samples[0].h_angle - left)/(right - left))
+	    .collect();
+
+	assert!(offsets.iter().all(|o| *o > 0.0 && *o < 0.5));
+	assert!(offsets.iter().any(|o| (o - offsets[0]).abs() > 0.1));
+    }
+
+    #[test]
+    fn hits_are_shaded_as_traced() {
+	let mut r = renderer(3000.0, Some(detail()));
+	let (hits, pixels) = r.trace_strip(X0, X0 + 2);
+	let shaded = r.shade_strip(X0, &hits);
+
+	assert!(pixels.iter().map(|p| p.as_array())
+		.eq(shaded.iter().map(|p| p.as_array())));
+
+	// Jittered, with several samples per pixel
+	r.sampling = Sampling::new(4, Pattern::Jittered);
+	let (hits, pixels) = r.trace_strip(X0, X0 + 2);
+
+	assert!(pixels.iter().map(|p| p.as_array())
+		.eq(r.shade_strip(X0, &hits).iter().map(|p| p.as_array())));
+    }
+
+    // Max-height pyramid of the terrain in front of the observer
+    fn pyramid() -> Arc<Pyramid> {
+	let o = observer();
+	Arc::new(Pyramid::with_heights(&Hills, o + coord(-100.0, -500.0),
+				       o + coord(23000.0, 500.0)))
+    }
+
+    // Land hit by a ray, and the distance
+    type Ray = Option<(Coord, f32)>;
+
+    // Hits of the samples of a column traced bottom-up, and of the same
+    // samples traced one by one from min_depth
+    fn column_hits(r: &mut Renderer) -> Vec<(Ray, Ray)> {
+	let mut hits = vec![Hits::default(); CONFIG.height as usize];
+	r.trace_column(800, 0, &Sampling::single(), &mut hits);
+
+	hits.iter().map(|h| {
+	    let s = h.samples[0];
+	    let ray_end = Coord::from_polar(CONFIG.max_depth, s.h_angle) + r.observer;
+	    let ray = r.render_ray(s.v_angle, 0.0, r.observer, r.observer_height,
+				   ray_end, CONFIG.min_depth);
+	    (s.hit.map(|hit| (hit.coord, hit.dist)), ray)
+	}).collect()
+    }
+
+    #[test]
+    fn column_hits_as_single_rays() {
+	let mut r = renderer(3000.0, Some(detail()));
+	for (column, single) in column_hits(&mut r) {
+	    assert_eq!(column, single);
+	}
+
+	// With the pyramid, looking at the side of the bowl with sky above
+	let mut r = renderer(20000.0, None);
+	r.set_pyramid(pyramid());
+	let hits = column_hits(&mut r);
+
+	assert!(hits.iter().any(|(column, _)| column.is_none()));
+	for (column, single) in hits {
+	    assert_eq!(column, single);
+	}
+    }
+
+    #[test]
+    fn pyramid_finds_same_hits() {
+	// Looking at the side of the bowl, with sky above it
+	let mut r = renderer(20000.0, None);
+	let fixed = r.trace_rays(X0, X1);
+	r.set_pyramid(pyramid());
+	let skipped = r.trace_rays(X0, X1);
+
+	assert!(fixed.iter().any(|f| f.is_none()));
+	assert!(fixed.iter().any(|f| f.is_some_and(|(_, d, _)| d > 5000.0)));
+
+	// The pyramid does not skip land, and the hits are refined between
+	// the samples. With fixed steps, the hits are up to one step beyond
+	// the terrain, or further if a thin ridge is missed.
+	let mut same = 0;
+	for (f, s) in fixed.iter().zip(&skipped) {
+	    match (f, s) {
+		(Some((fc, fd, step)), Some((sc, sd, _))) => {
+		    assert!(sd <= fd);
+		    if fd - sd <= *step {
+			assert!((*fc - *sc).abs() <= *step);
+			same += 1;
+		    }
+		},
+		(Some(_), None) => panic!("Land missed with the pyramid"),
+		_ => {},
+	    }
+	}
 
-        Ok(())
+	assert!(2*same > fixed.iter().filter(|f| f.is_some()).count());
     }
 }
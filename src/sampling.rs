@@ -0,0 +1,129 @@
+use crate::config::CONFIG;
+use crate::random;
+
+use hoydedata::{Error, Result};
+
+// Placement of the samples within each cell of the sample grid
+#[derive(Clone, Copy)]
+pub enum Pattern {
+    Stratified,
+    Jittered,
+}
+
+impl Pattern {
+    pub fn from(name: &str) -> Result<Pattern> {
+	match name {
+	    "stratified" => Ok(Pattern::Stratified),
+	    "jittered" => Ok(Pattern::Jittered),
+	    _ => Err(Error::Generic(
+		format!("Unknown sampling pattern {}", name))),
+	}
+    }
+}
+
+/*
+Sub-pixel sample positions. The pixel is divided into a grid of nx*ny cells
+with one sample in each cell. Stratified sampling puts the sample in the
+middle of the cell, and jittered sampling puts it at a random position
+within the cell. The grid is as square as possible, with at least the given
+number of samples.
+ */
+#[derive(Clone, Copy)]
+pub struct Sampling {
+    pattern: Pattern,
+    pub nx: u32,
+    pub ny: u32,
+}
+
+impl Sampling {
+    pub fn new(samples: u32, pattern: Pattern) -> Self {
+	let samples = samples.max(1);
+	let nx = ((samples as f32).sqrt().round() as u32).max(1);
+	let ny = samples.div_ceil(nx);
+
+	Self {
+	    pattern,
+	    nx,
+	    ny,
+	}
+    }
+
+    pub fn from_config() -> Result<Self> {
+	Ok(Sampling::new(CONFIG.samples.into(), Pattern::from(&CONFIG.sampling)?))
+    }
+
+    // One sample in the middle of the pixel
+    pub fn single() -> Self {
+	Sampling::new(1, Pattern::Stratified)
+    }
+
+    pub fn count(&self) -> u32 {
+	self.nx*self.ny
+    }
+
+    pub fn jittered(&self) -> bool {
+	matches!(self.pattern, Pattern::Jittered)
+    }
+
+    // Offset of sample i of n along one axis, relative to the pixel
+    // position, in the range -0.5 to 0.5. Offsets increase with i. The
+    // jitter is given by the key, e.g. a hash of the pixel position.
+    pub fn offset(&self, i: u32, n: u32, key: u64) -> f32 {
+	let u = match self.pattern {
+	    Pattern::Stratified => 0.5,
+	    Pattern::Jittered => random::uniform(&[key, i as u64]),
+	};
+
+	((i as f32) + u)/(n as f32) - 0.5
+    }
+
+    // Offset of sample i of n in the middle of its cell, whatever the
+    // pattern
+    pub fn centre_offset(i: u32, n: u32) -> f32 {
+	((i as f32) + 0.5)/(n as f32) - 0.5
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_has_enough_samples() {
+	for samples in 1..=20 {
+	    let s = Sampling::new(samples, Pattern::Stratified);
+	    assert!(s.count() >= samples);
+	    assert!(s.nx.abs_diff(s.ny) <= 1);
+	}
+
+	assert_eq!(Sampling::new(0, Pattern::Stratified).count(), 1);
+    }
+
+    #[test]
+    fn offsets_are_inside_pixel() {
+	let s = Sampling::new(9, Pattern::Stratified);
+
+	for n in 1..=4 {
+	    let mut prev = -0.5;
+	    for i in 0..n {
+		let o = s.offset(i, n, 0);
+		assert!(o > prev && o < 0.5);
+		assert_eq!(o, Sampling::centre_offset(i, n));
+		prev = o;
+	    }
+	}
+    }
+
+    #[test]
+    fn centre_offsets_are_symmetric() {
+	assert_eq!(Sampling::centre_offset(0, 1), 0.0);
+
+	for n in 1..=5 {
+	    for i in 0..n {
+		let sum = Sampling::centre_offset(i, n) +
+		    Sampling::centre_offset(n - 1 - i, n);
+		assert!(sum.abs() < 1e-6);
+	    }
+	}
+    }
+}
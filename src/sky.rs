@@ -0,0 +1,201 @@
+use crate::config::CONFIG;
+use crate::color::*;
+
+use hoydedata::Coord3;
+use std::f32::consts::PI;
+
+// Angular radius of the sun disc (radians)
+pub const SUN_RADIUS: f32 = 0.00465;
+
+// Brightness of the sky in zenith, and of the sun disc, relative to white
+const ZENITH_BRIGHTNESS: f32 = 0.55;
+const SUN_DISC_BRIGHTNESS: f32 = 20.0;
+
+// Angular width (radians) and strength of the glow around the sun
+const GLOW_WIDTH: f32 = 0.035;
+const GLOW_STRENGTH: f32 = 0.6;
+
+// Angular radius of the moon disc (radians), and brightness of the disc
+// relative to the moonlight
+const MOON_RADIUS: f32 = 0.0045;
+const MOON_DISC_BRIGHTNESS: f32 = 8.0;
+
+// Color of the twilight glow above the horizon towards the sun, and its
+// angular height (radians)
+const TWILIGHT_GLOW: Color = Color::new(1.0, 0.45, 0.15);
+const TWILIGHT_GLOW_HEIGHT: f32 = 0.12;
+
+// Night sky without moon
+const NIGHT_SKY: Color = Color::new(0.002, 0.004, 0.010);
+
+// Brightness of the sky relative to daylight, depending on the altitude of
+// the sun. Below the horizon, the light falls by a factor of ten for
+// approximately each 6 degrees of depression, through civil (0 to -6),
+// nautical (-6 to -12) and astronomical (-12 to -18) twilight.
+pub fn daylight(sun_alt: f32) -> f32 {
+    if sun_alt >= 0.0 {
+	return 1.0;
+    }
+
+    (sun_alt.to_degrees()/2.5).exp()
+}
+
+// Strength of the twilight glow, which is strongest when the sun is just
+// below the horizon.
+fn twilight_glow(sun_alt: f32) -> f32 {
+    let d = sun_alt.to_degrees() + 1.5;
+
+    (-(d*d)/16.0).exp()
+}
+
+/*
+Analytic daylight sky model (Preetham, Shirley and Smits: A practical
+analytic model for daylight, 1999). The luminance and chromaticity of the
+sky depends on the angle to zenith and the angle to the sun. Turbidity is
+derived from the haziness parameter. A sun disc and a circumsolar glow is
+added on top of the model.
+
+When the sun is below the horizon, the daylight sky fades, and a twilight
+glow appears above the horizon towards the sun. The moon lights up the night
+sky depending on its phase, and the moon disc is visible when it is up.
+ */
+pub struct Sky {
+    sun_ray: Coord3,
+    sun_alt: f32,
+    sun_color: Color,
+    moon_ray: Coord3,
+    moon_color: Color,
+    // Luminance (Y) and chromaticity (x, y) in zenith
+    zenith: [f32; 3],
+    // Perez function coefficients for Y, x and y, and the value of the
+    // functions in zenith
+    perez: [[f32; 5]; 3],
+    perez_zenith: [f32; 3],
+}
+
+// Perez sky luminance distribution function
+fn perez_function(c: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let cos_gamma = gamma.cos();
+
+    (1.0 + c[0]*(c[1]/cos_theta).exp())*
+	(1.0 + c[2]*(c[3]*gamma).exp() + c[4]*cos_gamma*cos_gamma)
+}
+
+impl Sky {
+    pub fn new(sun_ray: Coord3, sun_alt: f32, sun_color: Color,
+	       moon_ray: Coord3, moon_color: Color) -> Self {
+	let t = (1.7 + 2.0*CONFIG.haziness).clamp(1.7, 10.0);
+	let ts = 0.5*PI - sun_alt.max(0.0);
+	let ts2 = ts*ts;
+	let ts3 = ts2*ts;
+
+	let chi = (4.0/9.0 - t/120.0)*(PI - 2.0*ts);
+	let yz = (4.0453*t - 4.9710)*chi.tan() - 0.2155*t + 2.4192;
+
+	let xz = t*t*(0.00166*ts3 - 0.00375*ts2 + 0.00209*ts) +
+	    t*(-0.02903*ts3 + 0.06377*ts2 - 0.03202*ts + 0.00394) +
+	    (0.11693*ts3 - 0.21196*ts2 + 0.06052*ts + 0.25886);
+	let yz_c = t*t*(0.00275*ts3 - 0.00610*ts2 + 0.00317*ts) +
+	    t*(-0.04214*ts3 + 0.08970*ts2 - 0.04153*ts + 0.00516) +
+	    (0.15346*ts3 - 0.26756*ts2 + 0.06670*ts + 0.26688);
+
+	let perez = [
+	    [0.1787*t - 1.4630, -0.3554*t + 0.4275, -0.0227*t + 5.3251,
+	     0.1206*t - 2.5771, -0.0670*t + 0.3703],
+	    [-0.0193*t - 0.2592, -0.0665*t + 0.0008, -0.0004*t + 0.2125,
+	     -0.0641*t - 0.8989, -0.0033*t + 0.0452],
+	    [-0.0167*t - 0.2608, -0.0950*t + 0.0092, -0.0079*t + 0.2102,
+	     -0.0441*t - 1.6537, -0.0109*t + 0.0529],
+	];
+
+	let perez_zenith = [
+	    perez_function(&perez[0], 1.0, ts),
+	    perez_function(&perez[1], 1.0, ts),
+	    perez_function(&perez[2], 1.0, ts),
+	];
+
+	Self {
+	    sun_ray,
+	    sun_alt,
+	    sun_color,
+	    moon_ray,
+	    moon_color,
+	    zenith: [yz, xz, yz_c],
+	    perez,
+	    perez_zenith,
+	}
+    }
+
+    /*
+    Color of the sky in a direction.
+    view:      unit vector of the view direction
+    elevation: angle above the horizon, corrected for the horizon being
+               lower than the tangent direction from the observer
+    sun_disc:  whether to draw the sun disc
+     */
+    pub fn color(&self, view: Coord3, elevation: f32, sun_disc: bool) -> Color {
+	// The model is not defined below the horizon
+	let cos_theta = elevation.sin().max(0.01);
+	let cos_gamma = view.dot(self.sun_ray).clamp(-1.0, 1.0);
+	let gamma = cos_gamma.acos();
+
+	let mut v = [0.0; 3];
+	for (i, vi) in v.iter_mut().enumerate() {
+	    *vi = self.zenith[i]*perez_function(&self.perez[i], cos_theta, gamma)/
+		self.perez_zenith[i];
+	}
+
+	// Relative luminance, 1 in zenith
+	let lum = v[0]/self.zenith[0];
+	let (x, y) = (v[1], v[2]);
+
+	// xyY -> XYZ -> linear sRGB
+	let cx = x/y*lum;
+	let cy = lum;
+	let cz = (1.0 - x - y)/y*lum;
+	let r = 3.2406*cx - 1.5372*cy - 0.4986*cz;
+	let g = -0.9689*cx + 1.8758*cy + 0.0415*cz;
+	let b = 0.0557*cx - 0.2040*cy + 1.0570*cz;
+
+	// The daylight sky, dimmed by twilight. At night, the sky is lit by
+	// the moon.
+	let moon = self.moon_color;
+	let [_, moon_level, _] = moon.as_array();
+	let brightness = ZENITH_BRIGHTNESS*CONFIG.sky_lum;
+
+	let mut color = Color::new(r.max(0.0), g.max(0.0), b.max(0.0))*
+	    (brightness*(daylight(self.sun_alt) + moon_level)) +
+	    NIGHT_SKY*CONFIG.sky_lum;
+
+	// Twilight glow towards the sun
+	let glow = twilight_glow(self.sun_alt);
+	if glow > 0.001 {
+	    // Cosine of horizontal angle between view and sun
+	    let up = Coord3::new(0.0, 0.0, 1.0);
+	    let vz = view.dot(up);
+	    let sz = self.sun_ray.dot(up);
+	    let cos_h = (cos_gamma - vz*sz)/
+		((1.0 - vz*vz)*(1.0 - sz*sz)).sqrt().max(0.0001);
+
+	    color += TWILIGHT_GLOW*(brightness*glow*
+				    ((cos_h - 1.0)/0.5).exp()*
+				    (-elevation.max(0.0)/TWILIGHT_GLOW_HEIGHT).exp());
+	}
+
+	// Sun disc and glow, colored by the sunlight through the atmosphere
+	let sun = self.sun_color;
+	color += sun*(GLOW_STRENGTH*(-gamma/GLOW_WIDTH).exp());
+	if sun_disc && gamma < SUN_RADIUS {
+	    color += sun*SUN_DISC_BRIGHTNESS;
+	}
+
+	// Moon disc and glow
+	let gamma_m = view.dot(self.moon_ray).clamp(-1.0, 1.0).acos();
+	color += moon*(GLOW_STRENGTH*(-gamma_m/GLOW_WIDTH).exp());
+	if gamma_m < MOON_RADIUS {
+	    color += moon*MOON_DISC_BRIGHTNESS;
+	}
+
+	color
+    }
+}
@@ -0,0 +1,76 @@
+// Stereo pairs, rendered from two observer positions
+use crate::config::CONFIG;
+
+use hoydedata::{Error, Result};
+
+// Layout of the stereo pair in the image
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stereo {
+    None,
+    // Left eye image to the left, for parallel viewing
+    SideBySide,
+    // Right eye image to the left, for cross-eyed viewing
+    CrossEye,
+    // Red/cyan anaglyph, for glasses with red filter on the left eye
+    Anaglyph,
+}
+
+impl Stereo {
+    pub fn from(name: &str) -> Result<Stereo> {
+	match name {
+	    "none" => Ok(Stereo::None),
+	    "side_by_side" => Ok(Stereo::SideBySide),
+	    "cross_eye" => Ok(Stereo::CrossEye),
+	    "anaglyph" => Ok(Stereo::Anaglyph),
+	    _ => Err(Error::Generic(format!("Unknown stereo mode {}", name))),
+	}
+    }
+
+    pub fn from_config() -> Result<Stereo> {
+	Stereo::from(&CONFIG.stereo)
+    }
+
+    // The eyes to render
+    pub fn eyes(&self) -> Vec<Eye> {
+	match self {
+	    Stereo::None => vec![Eye::Centre],
+	    _ => vec![Eye::Left, Eye::Right],
+	}
+    }
+
+    // Size of the whole image
+    pub fn image_size(&self) -> (u32, u32) {
+	match self {
+	    Stereo::SideBySide | Stereo::CrossEye => (2*CONFIG.width, CONFIG.height),
+	    _ => (CONFIG.width, CONFIG.height),
+	}
+    }
+}
+
+// The observer position of a view
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Eye {
+    Centre,
+    Left,
+    Right,
+}
+
+impl Eye {
+    // Sideways offset (meters) from the observer, to the right
+    pub fn offset(&self) -> f32 {
+	match self {
+	    Eye::Centre => 0.0,
+	    Eye::Left => -0.5*CONFIG.stereo_baseline,
+	    Eye::Right => 0.5*CONFIG.stereo_baseline,
+	}
+    }
+
+    // Number of the eye, e.g. in the geometry buffer
+    pub fn code(&self) -> u32 {
+	match self {
+	    Eye::Centre => 0,
+	    Eye::Left => 1,
+	    Eye::Right => 2,
+	}
+    }
+}
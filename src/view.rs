@@ -0,0 +1,40 @@
+use crate::config::CONFIG;
+use hoydedata::Coord;
+
+/*
+Camera position and framing of one image. A single image uses the view from
+config, while an animation has one view per frame.
+ */
+#[derive(Clone)]
+pub struct View {
+    pub observer: Coord,
+    pub target: Coord,
+    // Observer height above sea level, instead of the terrain height. The
+    // observer is never below the terrain.
+    pub observer_height: Option<f32>,
+    pub time: String,
+    pub width_angle: f32,
+}
+
+impl View {
+    pub fn from_config() -> Self {
+	Self {
+	    observer: CONFIG.observer,
+	    target: CONFIG.target,
+	    observer_height: None,
+	    time: CONFIG.time.clone(),
+	    width_angle: CONFIG.width_angle,
+	}
+    }
+
+    // Check if two views have the same camera, and only differ in time.
+    // The rays of the views then hit the terrain at the same points.
+    pub fn same_camera(&self, other: &View) -> bool {
+	self.observer.e == other.observer.e &&
+	    self.observer.n == other.observer.n &&
+	    self.target.e == other.target.e &&
+	    self.target.n == other.target.n &&
+	    self.observer_height == other.observer_height &&
+	    self.width_angle == other.width_angle
+    }
+}
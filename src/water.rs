@@ -0,0 +1,537 @@
+// Water: the water mask raster for finding lakes, the color of the water,
+// and the waves of the water surface
+use crate::color::*;
+use crate::config::CONFIG;
+use crate::sky::SUN_RADIUS;
+use crate::random;
+
+use hoydedata::{Coord, Error, Result};
+use rand::Rng;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, BufReader};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+
+/*
+A single band GeoTIFF in the same coordinate system as the maps (UTM zone
+33). Its position is given by the model tiepoint and pixel scale tags.
+Pixels with the value of the GDAL nodata tag have no value.
+ */
+struct GeoRaster {
+    width: u32,
+    height: u32,
+    // Coordinate of the upper left corner, and pixel size (meters)
+    e0: f64,
+    n0: f64,
+    pixel_e: f64,
+    pixel_n: f64,
+    nodata: Option<f32>,
+    values: Vec<f32>,
+}
+
+fn invalid(msg: &str) -> tiff::TiffError {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string()).into()
+}
+
+impl GeoRaster {
+    fn load(file: &str) -> Result<GeoRaster> {
+	GeoRaster::read(file)
+	    .map_err(|e| Error::Generic(format!("{}: {}", file, e)))
+    }
+
+    fn read(file: &str) -> tiff::TiffResult<GeoRaster> {
+	let mut decoder = Decoder::new(BufReader::new(File::open(file)?))?;
+	let (width, height) = decoder.dimensions()?;
+	let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag)?;
+	let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag)?;
+
+	if scale.len() < 2 || tiepoint.len() < 6 {
+	    return Err(invalid("Bad georeferencing"));
+	}
+
+	let nodata = match decoder.find_tag(Tag::GdalNodata)? {
+	    Some(v) => v.into_string()?.trim_matches(char::from(0)).trim()
+		.parse::<f32>().ok(),
+	    None => None,
+	};
+
+	let values: Vec<f32> = match decoder.read_image()? {
+	    DecodingResult::U8(d) => d.iter().map(|v| *v as f32).collect(),
+	    DecodingResult::U16(d) => d.iter().map(|v| *v as f32).collect(),
+	    DecodingResult::U32(d) => d.iter().map(|v| *v as f32).collect(),
+	    DecodingResult::I8(d) => d.iter().map(|v| *v as f32).collect(),
+	    DecodingResult::I16(d) => d.iter().map(|v| *v as f32).collect(),
+	    DecodingResult::I32(d) => d.iter().map(|v| *v as f32).collect(),
+	    DecodingResult::F32(d) => d,
+	    DecodingResult::F64(d) => d.iter().map(|v| *v as f32).collect(),
+	    _ => return Err(invalid("Unsupported sample format")),
+	};
+
+	// The tiepoint maps raster position (i, j) to model position (x, y)
+	let (pixel_e, pixel_n) = (scale[0], scale[1]);
+
+	Ok(GeoRaster {
+	    width,
+	    height,
+	    e0: tiepoint[3] - tiepoint[0]*pixel_e,
+	    n0: tiepoint[4] + tiepoint[1]*pixel_n,
+	    pixel_e,
+	    pixel_n,
+	    nodata,
+	    values,
+	})
+    }
+
+    // Value at a coordinate, or None outside the raster and for nodata
+    fn value(&self, c: &Coord) -> Option<f32> {
+	let col = ((c.e as f64 - self.e0)/self.pixel_e).floor();
+	let row = ((self.n0 - c.n as f64)/self.pixel_n).floor();
+
+	if col < 0.0 || row < 0.0 ||
+	    col >= self.width as f64 || row >= self.height as f64 {
+	    return None;
+	}
+
+	// With more than one sample per pixel, we use the first
+	let samples = self.values.len()/((self.width*self.height) as usize).max(1);
+	let v = self.values[((row as usize)*(self.width as usize) + col as usize)*samples];
+
+	if v.is_nan() || Some(v) == self.nodata {
+	    return None;
+	}
+
+	Some(v)
+    }
+}
+
+/*
+The type of a body of water, which gives its color. Clear sea is deep blue,
+and lightens to turquoise over a light bottom. Fjords are darker and
+greener, with fresh water and plankton, and the bottom is seen only close
+to the shore. Glacial silt makes the water milky turquoise, whatever the
+depth.
+ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum WaterType {
+    Sea,
+    Fjord,
+    Glacial,
+}
+
+impl WaterType {
+    pub fn from(name: &str) -> Result<WaterType> {
+	match name {
+	    "sea" => Ok(WaterType::Sea),
+	    "fjord" => Ok(WaterType::Fjord),
+	    "glacial" => Ok(WaterType::Glacial),
+	    _ => Err(Error::Generic(format!("Unknown water type {}", name))),
+	}
+    }
+
+    // Color of deep and shallow water, and the depth (meters) the bottom
+    // can be seen to
+    fn colors(&self) -> (Color, Color, f32) {
+	match self {
+	    WaterType::Sea => (SEA, SEA_SHALLOW, 8.0),
+	    WaterType::Fjord => (FJORD, FJORD_SHALLOW, 4.0),
+	    WaterType::Glacial => (GLACIAL, GLACIAL_SHALLOW, 1.5),
+	}
+    }
+
+    // Color of the water at a depth (meters). Without a known depth, the
+    // water is deep.
+    pub fn color(&self, depth: Option<f32>) -> Color {
+	let (deep, shallow, visible) = self.colors();
+
+	match depth {
+	    Some(d) => deep.blend(&shallow, (-d.max(0.0)/visible).exp()),
+	    None => deep,
+	}
+    }
+}
+
+/*
+A GeoTIFF with water in the non-zero pixels, see GeoRaster. The value of a
+pixel can also give the type of the water: 2 for sea, 3 for fjord and 4 for
+glacial water. Other values give water_type or lake_type in config.
+ */
+pub struct WaterMask {
+    raster: GeoRaster,
+}
+
+impl WaterMask {
+    pub fn load(file: &str) -> Result<WaterMask> {
+	Ok(WaterMask {
+	    raster: GeoRaster::load(file)?,
+	})
+    }
+
+    // Check if there is water at a coordinate, i.e. a non-zero value.
+    // Outside the raster and in nodata pixels, there is no water.
+    pub fn is_water(&self, c: &Coord) -> bool {
+	self.raster.value(c).is_some_and(|v| v != 0.0)
+    }
+
+    // Type of the water at a coordinate, if given by the mask
+    pub fn water_type(&self, c: &Coord) -> Option<WaterType> {
+	match self.raster.value(c) {
+	    Some(2.0) => Some(WaterType::Sea),
+	    Some(3.0) => Some(WaterType::Fjord),
+	    Some(4.0) => Some(WaterType::Glacial),
+	    _ => None,
+	}
+    }
+}
+
+/*
+A GeoTIFF with the depth (meters) of the water, see GeoRaster. Negative
+values are taken as heights of the bottom below the water surface, so both
+depth and bathymetry heights can be used.
+ */
+pub struct Bathymetry {
+    raster: GeoRaster,
+}
+
+impl Bathymetry {
+    pub fn load(file: &str) -> Result<Bathymetry> {
+	Ok(Bathymetry {
+	    raster: GeoRaster::load(file)?,
+	})
+    }
+
+    // Depth of the water at a coordinate, or None outside the raster
+    pub fn depth(&self, c: &Coord) -> Option<f32> {
+	self.raster.value(c).map(|v| v.abs())
+    }
+}
+
+// A vector as east, north and up components
+pub type Vec3 = [f32; 3];
+
+// Acceleration of gravity (m/s^2)
+const G: f32 = 9.81;
+
+// Number of waves in the wave field, and the seed of their random
+// directions and phases
+const WAVES: usize = 64;
+const WAVE_SEED: u64 = 1;
+
+// Shortest wavelength (meters) in the wave field. The coordinates are not
+// more accurate than half a meter, so shorter waves are left to the facets.
+const MIN_WAVELENGTH: f32 = 2.0;
+
+// Share of the mean square slope of the waves which is in the wave field.
+// The rest is in the facets.
+const WAVE_FIELD_SHARE: f32 = 0.5;
+
+// Smallest cosine of the view angle from zenith in the glint model, which
+// does not hold for grazing views
+const GLINT_MIN_COS: f32 = 0.05;
+
+fn dot(a: Vec3, b: Vec3) -> f32 {
+    a[0]*b[0] + a[1]*b[1] + a[2]*b[2]
+}
+
+fn normalize(a: Vec3) -> Vec3 {
+    let l = dot(a, a).sqrt();
+    [a[0]/l, a[1]/l, a[2]/l]
+}
+
+// Unit vector of a direction given by directional and vertical angle
+pub fn direction(h_angle: f32, v_angle: f32) -> Vec3 {
+    [h_angle.cos()*v_angle.cos(), h_angle.sin()*v_angle.cos(), v_angle.sin()]
+}
+
+// Direction of a vector in the local frame at a distance along the ground
+// in direction h_angle. The vertical there is tilted by delta (the
+// distance divided by the earth radius) away from the observer.
+pub fn tilt(v: Vec3, h_angle: f32, delta: f32) -> Vec3 {
+    let u = [h_angle.cos(), h_angle.sin()];
+    let a = v[0]*u[0] + v[1]*u[1];
+    let (a1, z1) = (a*delta.cos() - v[2]*delta.sin(), v[2]*delta.cos() + a*delta.sin());
+
+    [v[0] + (a1 - a)*u[0], v[1] + (a1 - a)*u[1], z1]
+}
+
+// Reflection of a direction in a surface with a given normal
+pub fn reflect(v: Vec3, normal: Vec3) -> Vec3 {
+    let d = 2.0*dot(v, normal);
+    [v[0] - d*normal[0], v[1] - d*normal[1], v[2] - d*normal[2]]
+}
+
+// Mean square slope of the sea surface at a wind speed (Cox and Munk)
+fn cox_munk(wind_speed: f32) -> f32 {
+    0.003 + 0.00512*wind_speed.max(0.0)
+}
+
+// Reflection rate of water, by Schlick's approximation, for light with a
+// given cosine of the angle to the surface normal.
+pub fn fresnel(cos: f32) -> f32 {
+    let r0 = 0.0200593121995248; // ((1.33 - 1)/(1.33 + 1))^2
+
+    r0 + (1.0 - r0)*(1.0 - cos.clamp(0.0, 1.0)).powi(5)
+}
+
+/*
+Wave slopes of the water surface. The surface is seen as small facets with
+normally distributed slopes (Cox and Munk, 1954). The mean square slope
+grows with the wind speed, from 0.003 on calm water. With wind, half of the
+slope is in the waves of the wave field, and the facets are tilted by the
+slope of the wave field where they are. Without wind_speed in config, the
+slopes are given by water_ripples, which gives the smooth surface of
+sheltered fjords and lakes. The sun disc is not a point, and widens the
+glint as if the slopes were a bit larger.
+ */
+pub struct Facets {
+    // Mean square slope
+    variance: f32,
+}
+
+impl Facets {
+    pub fn from_config() -> Facets {
+	let variance = match CONFIG.wind_speed {
+	    Some(w) => (1.0 - WAVE_FIELD_SHARE)*cox_munk(w),
+	    None => (0.01*CONFIG.water_ripples*0.25*PI).powi(2),
+	};
+
+	Facets {
+	    variance: variance + (0.5*SUN_RADIUS).powi(2),
+	}
+    }
+
+    // Normal of a random facet, on waves with a given slope, from a point u
+    // in the unit square. The slopes of waves filtered out of the wave
+    // field add to the variance.
+    pub fn sample(&self, u: [f32; 2], slope: [f32; 2], filtered: f32) -> Vec3 {
+	// Normally distributed slopes (Box-Muller), with half of the
+	// variance along each axis
+	let variance = self.variance + filtered;
+	let r = (-variance*(1.0 - u[0]).ln()).sqrt();
+	let a = 2.0*PI*u[1];
+
+	normalize([-slope[0] - r*a.cos(), -slope[1] - r*a.sin(), 1.0])
+    }
+
+    /*
+    Sun glint: the radiance of the sun reflected in the facets, relative to
+    the sunlight on a surface facing the sun. view is the view direction,
+    and sun the direction towards the sun, both in the local frame at the
+    water surface. The facets are on waves with a given slope, as in
+    sample().
+     */
+    pub fn glint(&self, view: Vec3, sun: Vec3, slope: [f32; 2], filtered: f32)
+		 -> f32 {
+	let eye = [-view[0], -view[1], -view[2]];
+	if eye[2] <= 0.0 || sun[2] <= 0.0 {
+	    return 0.0;
+	}
+
+	// The facets reflecting the sun towards the eye have the half vector
+	// as normal
+	let h = normalize([eye[0] + sun[0], eye[1] + sun[1], eye[2] + sun[2]]);
+	let cos_b = h[2];
+	let (sx, sy) = (-h[0]/cos_b - slope[0], -h[1]/cos_b - slope[1]);
+	let variance = self.variance + filtered;
+	let p = (-(sx*sx + sy*sy)/variance).exp()/(PI*variance);
+
+	PI*fresnel(dot(sun, h))*p/(4.0*eye[2].max(GLINT_MIN_COS)*cos_b.powi(4))
+    }
+}
+
+// A wave of the wave field: wave vector (radians per meter), amplitude
+// (meters) and phase
+struct Wave {
+    k: [f32; 2],
+    amplitude: f32,
+    phase: f32,
+}
+
+/*
+Wave field of the water surface, driven by the wind. The field is a sum of
+waves with wavelengths and directions drawn from the Phillips spectrum
+(Tessendorf: Simulating ocean water, 2001), which has its peak at longer
+waves with stronger wind, and waves travelling along the wind. The field is
+scaled to have WAVE_FIELD_SHARE of the mean square slope of Cox and Munk.
+Since the field is the same everywhere, neighbour pixels see the same
+waves, and the reflections are streaked across the wind. Only the slopes
+of the waves are used, not their heights.
+
+Without wind_speed in config, the field has no waves.
+ */
+pub struct WaveField {
+    waves: Vec<Wave>,
+}
+
+impl WaveField {
+    pub fn from_config() -> WaveField {
+	let speed = match CONFIG.wind_speed {
+	    Some(w) if w > 0.0 => w,
+	    _ => return WaveField { waves: Vec::new() },
+	};
+
+	// Direction the waves travel, as directional angle. The wind
+	// direction is where the wind comes from, in degrees from north,
+	// clockwise.
+	let heading = 1.5*PI - CONFIG.wind_direction.to_radians();
+
+	// Largest wave from the wind, and the range of wave numbers
+	let l = speed*speed/G;
+	let k_max = 2.0*PI/MIN_WAVELENGTH;
+	let k_min = (0.1/l).min(0.1*k_max);
+	let log_range = (k_max/k_min).ln();
+
+	let mut rng = random::generator(&[WAVE_SEED]);
+	let mut waves = Vec::with_capacity(WAVES);
+	let mut mean_square_slope = 0.0;
+
+	for i in 0..WAVES {
+	    // Wave numbers evenly spread on a log scale, with random
+	    // directions around the heading
+	    let k = k_min*(log_range*((i as f32) + rng.random::<f32>())/(WAVES as f32)).exp();
+	    let dk = k*log_range/(WAVES as f32);
+	    let theta = heading + PI*(rng.random::<f32>() - 0.5);
+	    let cos = (theta - heading).cos();
+
+	    // Phillips spectrum, over the half circle of directions
+	    let p = (-1.0/(k*l*k*l)).exp()/k.powi(4)*cos*cos;
+	    let amplitude = (2.0*p*k*dk*PI).sqrt();
+
+	    mean_square_slope += 0.5*(amplitude*k).powi(2);
+	    waves.push(Wave {
+		k: [k*theta.cos(), k*theta.sin()],
+		amplitude,
+		phase: 2.0*PI*rng.random::<f32>(),
+	    });
+	}
+
+	if mean_square_slope > 0.0 {
+	    let scale = (WAVE_FIELD_SHARE*cox_munk(speed)/mean_square_slope).sqrt();
+	    for w in waves.iter_mut() {
+		w.amplitude *= scale;
+	    }
+	}
+
+	WaveField {
+	    waves,
+	}
+    }
+
+    /*
+    Slope of the waves (east and north gradient) at a coordinate, seen in
+    direction h_angle at r_angle above the water. The footprint of a pixel
+    on the water (meters) is stretched by 1/sin(r_angle) along the view,
+    so at grazing angles, waves travelling along the view are filtered much
+    more than waves across it. Waves shorter than two footprints in their
+    direction cannot be sampled, and are faded out from four footprints.
+    Returns the slope, and the mean square slope of the waves which are
+    faded out, so that the facets can take their place.
+     */
+    pub fn slope(&self, c: &Coord, footprint: f32, h_angle: f32, r_angle: f32)
+		 -> ([f32; 2], f32) {
+	let mut slope = [0.0; 2];
+	let mut filtered = 0.0;
+
+	let along = footprint/r_angle.sin().max(1e-3);
+	let (cos, sin) = (h_angle.cos(), h_angle.sin());
+
+	for w in &self.waves {
+	    let k = (w.k[0]*w.k[0] + w.k[1]*w.k[1]).sqrt();
+
+	    // Wave number times footprint, with the parts of the wave vector
+	    // along and across the view
+	    let k_along = w.k[0]*cos + w.k[1]*sin;
+	    let k_across = w.k[1]*cos - w.k[0]*sin;
+	    let kf = ((k_along*along).powi(2) + (k_across*footprint).powi(2)).sqrt();
+	    let t = (2.0*kf/PI - 1.0).clamp(0.0, 1.0);
+	    let fade = 1.0 - t*t*(3.0 - 2.0*t);
+
+	    // The phase in double precision, since the coordinates are large
+	    let arg = ((w.k[0] as f64)*(c.e as f64) + (w.k[1] as f64)*(c.n as f64) +
+		       (w.phase as f64)).rem_euclid(2.0*std::f64::consts::PI) as f32;
+	    let a = w.amplitude*fade*arg.cos();
+
+	    slope[0] += a*w.k[0];
+	    slope[1] += a*w.k[1];
+	    filtered += 0.5*(1.0 - fade*fade)*(w.amplitude*k).powi(2);
+	}
+
+	(slope, filtered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+	for i in 0..3 {
+	    assert!((a[i] - b[i]).abs() < 1e-6, "{:?} != {:?}", a, b);
+	}
+    }
+
+    #[test]
+    fn reflect_in_level_surface() {
+	let v = direction(0.3, -0.2);
+	let r = reflect(v, [0.0, 0.0, 1.0]);
+
+	assert_near(r, direction(0.3, 0.2));
+	assert_near(reflect(r, [0.0, 0.0, 1.0]), v);
+    }
+
+    #[test]
+    fn reflect_keeps_length() {
+	let normal = normalize([0.1, -0.2, 1.0]);
+	let r = reflect(direction(1.0, -0.1), normal);
+
+	assert!((dot(r, r) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fresnel_from_normal_to_grazing() {
+	assert!((fresnel(1.0) - 0.02).abs() < 1e-3);
+	assert_eq!(fresnel(0.0), 1.0);
+	assert!(fresnel(0.5) > fresnel(0.6));
+
+	// Outside 0 to 1, the cosine is clamped
+	assert_eq!(fresnel(-0.5), fresnel(0.0));
+	assert_eq!(fresnel(1.5), fresnel(1.0));
+    }
+
+    #[test]
+    fn tilt_by_curvature() {
+	let v = direction(0.7, -0.1);
+	assert_near(tilt(v, 0.7, 0.0), v);
+
+	// A level view at the observer is rising away from the observer at
+	// a distance, since the earth curves away below it
+	let delta = 0.01;
+	assert_near(tilt(direction(0.7, 0.0), 0.7, delta), direction(0.7, delta));
+
+	// Across the direction of the tilt, nothing changes
+	let across = direction(0.7 + 0.5*PI, 0.0);
+	assert_near(tilt(across, 0.7, delta), across);
+    }
+
+    #[test]
+    fn waves_along_view_are_filtered_at_grazing_angles() {
+	let field = WaveField {
+	    waves: vec![Wave { k: [1.0, 0.0], amplitude: 0.1, phase: 0.0 }],
+	};
+	let c = Coord::from_polar(0.0, 0.0);
+	let all = 0.5*(0.1_f32*1.0).powi(2);
+
+	// Seen from above, the wave is longer than the footprint
+	let (_, filtered) = field.slope(&c, 0.5, 0.0, 0.5*PI);
+	assert_eq!(filtered, 0.0);
+
+	// At a grazing angle, the footprint along the view is long, so the
+	// wave along the view is filtered, but not the wave across it
+	let (slope, filtered) = field.slope(&c, 0.5, 0.0, 0.05);
+	assert!((filtered - all).abs() < 1e-6);
+	assert_eq!(slope, [0.0, 0.0]);
+
+	let (_, filtered) = field.slope(&c, 0.5, 0.5*PI, 0.05);
+	assert!(filtered < 1e-6);
+    }
+}